@@ -0,0 +1,92 @@
+use alloc::format;
+use alloc::string::String;
+use core::ffi::{c_char, CStr};
+use pd::sys::log::println;
+
+use crate::sim::Material;
+
+// Simulator-only command hook: text sent over serial drives the game
+// without touching the device controls
+
+// Last message received, the callback runs between frames
+static mut PENDING: Option<String> = None;
+
+unsafe extern "C" fn on_serial_message(data: *const c_char) {
+    let text = CStr::from_ptr(data).to_string_lossy().into_owned();
+    *core::ptr::addr_of_mut!(PENDING) = Some(text);
+}
+
+pub fn install() {
+    unsafe {
+        pd::sys::api!(system.setSerialMessageCallback)(Some(on_serial_message));
+    }
+}
+
+pub enum Command {
+    // Same as releasing B, or remove just one material
+    Clear(Option<Material>),
+    // Toggle the brush outline
+    Cursor,
+    // Print the current density over serial
+    Density,
+    // Print the box of cells the simulation changed since last asked
+    Dirty,
+    // Toggle the per-phase frame timings in the HUD
+    Profile,
+    // Drop a reproducible random field on top of the sand
+    Scatter { count: usize, seed: u64 },
+    // Restart the simulation rng from a fixed seed
+    Seed(u64),
+    // Toggle grains wrapping around the left and right edges
+    Wrap,
+    // Let resting grains evaporate after this many frames, None turns it off
+    Evaporate(Option<u16>),
+    // Toggle drawing long-resting sand with a lighter dither
+    Shade,
+    // Percent of the brush cells each stamp fills
+    Spray(u8),
+    // Most physics passes per frame, the density heuristic scales down from it
+    Substeps(u8),
+}
+
+pub fn poll() -> Option<Command> {
+    let text = unsafe { (*core::ptr::addr_of_mut!(PENDING)).take() }?;
+    let command = parse(&text);
+    if command.is_none() {
+        println(format!("unknown command: {text}"));
+    }
+    command
+}
+
+fn parse(text: &str) -> Option<Command> {
+    let mut words = text.split_whitespace();
+    match words.next()? {
+        "clear" => match words.next() {
+            None => Some(Command::Clear(None)),
+            Some("sand") => Some(Command::Clear(Some(Material::Sand))),
+            Some("water") => Some(Command::Clear(Some(Material::Water))),
+            Some("ash") => Some(Command::Clear(Some(Material::Ash))),
+            Some("stone") => Some(Command::Clear(Some(Material::Stone))),
+            Some(_) => None,
+        },
+        "cursor" => Some(Command::Cursor),
+        "density" => Some(Command::Density),
+        "dirty" => Some(Command::Dirty),
+        "profile" => Some(Command::Profile),
+        "scatter" => {
+            let count = words.next()?.parse().ok()?;
+            let seed = words.next().map_or(Some(0), |w| w.parse().ok())?;
+            Some(Command::Scatter { count, seed })
+        }
+        "seed" => Some(Command::Seed(words.next()?.parse().ok()?)),
+        "shade" => Some(Command::Shade),
+        "spray" => Some(Command::Spray(words.next()?.parse().ok()?)),
+        "substeps" => Some(Command::Substeps(words.next()?.parse().ok()?)),
+        "wrap" => Some(Command::Wrap),
+        "evaporate" => match words.next()? {
+            "off" => Some(Command::Evaporate(None)),
+            ticks => Some(Command::Evaporate(Some(ticks.parse().ok()?))),
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,69 @@
+use alloc::format;
+use alloc::vec::Vec;
+
+use playdate::graphics::Graphics;
+
+use crate::sim::{COLUMNS, PIXEL_WIDTH, ROWS};
+
+// Screenshots are binary PBM (P4) files, which most image tools open as is.
+// PBM rows are packed MSB first like the frame, but without the frame's
+// padding bytes, and a set bit is black where the display shows it white.
+const ROW_BYTES: usize = PIXEL_WIDTH.div_ceil(8);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExportError {
+    NoFrame,
+    CannotWrite,
+}
+
+// A frame shorter than the screen is padded out with black rows
+pub fn encode_frame(frame: &[u8]) -> Vec<u8> {
+    let header = format!("P4\n{PIXEL_WIDTH} {ROWS}\n");
+    let mut image = Vec::with_capacity(header.len() + ROW_BYTES * ROWS);
+    image.extend_from_slice(header.as_bytes());
+    for y in 0..ROWS {
+        let start = (y * COLUMNS).min(frame.len());
+        let row = &frame[start..(start + ROW_BYTES).min(frame.len())];
+        image.extend(row.iter().map(|&byte| !byte));
+        image.resize(image.len() + ROW_BYTES - row.len(), 0xFF);
+    }
+    image
+}
+
+// Save what is on screen right now into the game's data folder
+pub fn export_frame(path: &str) -> Result<(), ExportError> {
+    let frame = Graphics::Cached()
+        .get_frame()
+        .map_err(|_| ExportError::NoFrame)?;
+    playdate::fs::write(path, encode_frame(frame)).map_err(|_| ExportError::CannotWrite)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::BUFFER_SIZE;
+
+    #[test]
+    fn frames_encode_as_inverted_pbm_without_padding() {
+        let mut frame = [0u8; BUFFER_SIZE];
+        frame[0] = 0x80;
+        frame[ROW_BYTES] = 0xFF; // Padding, left out of the image
+        frame[COLUMNS + 1] = 0x0F;
+        let image = encode_frame(&frame);
+
+        let header = b"P4\n400 240\n";
+        assert_eq!(&image[..header.len()], header);
+        let pixels = &image[header.len()..];
+        assert_eq!(pixels.len(), ROW_BYTES * ROWS);
+        assert_eq!(pixels[0], 0x7F);
+        assert!(pixels[1..ROW_BYTES].iter().all(|&byte| byte == 0xFF));
+        assert_eq!(pixels[ROW_BYTES + 1], 0xF0);
+
+        // Rows missing from a short frame come out black
+        let image = encode_frame(&frame[..COLUMNS]);
+        assert_eq!(image.len(), header.len() + ROW_BYTES * ROWS);
+        assert!(image[header.len() + ROW_BYTES..]
+            .iter()
+            .all(|&byte| byte == 0xFF));
+    }
+}
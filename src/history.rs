@@ -0,0 +1,122 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+// Snapshots kept, the oldest is dropped once full
+const HISTORY_LEN: usize = 100;
+
+// Ring of run-length encoded field snapshots, mostly empty or mostly full
+// fields shrink to a few hundred bytes. Each snapshot holds every plane it
+// was pushed with, so materials come back along with the grains. A noisy
+// plane the runs would only grow is kept as it is.
+pub struct History {
+    snapshots: VecDeque<Vec<Vec<u8>>>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            snapshots: VecDeque::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    pub fn push(&mut self, planes: &[&[u8]]) {
+        if self.snapshots.len() == HISTORY_LEN {
+            self.snapshots.pop_front();
+        }
+        self.snapshots
+            .push_back(planes.iter().map(|plane| encode(plane)).collect());
+    }
+
+    // Index 0 is the oldest snapshot. Fills as many planes as `out` holds, in
+    // the order they were pushed. Returns false when there is no such
+    // snapshot.
+    pub fn restore(&self, index: usize, out: &mut [&mut [u8]]) -> bool {
+        let Some(planes) = self.snapshots.get(index) else {
+            return false;
+        };
+
+        for (plane, out) in planes.iter().zip(out) {
+            let Some((&tag, data)) = plane.split_first() else {
+                continue;
+            };
+            if tag == RAW {
+                let len = data.len().min(out.len());
+                out[..len].copy_from_slice(&data[..len]);
+                continue;
+            }
+
+            let mut pos = 0;
+            for run in data.chunks_exact(2) {
+                let end = (pos + usize::from(run[0])).min(out.len());
+                out[pos..end].fill(run[1]);
+                pos = end;
+            }
+        }
+        true
+    }
+}
+
+// First byte of a stored plane, telling how the rest is laid out
+const RUNS: u8 = 0;
+const RAW: u8 = 1;
+
+// (run length, byte) pairs, or the bytes as they are when that is shorter
+fn encode(buffer: &[u8]) -> Vec<u8> {
+    let mut runs = alloc::vec![RUNS];
+    let mut bytes = buffer.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        let mut len = 1u8;
+        while len < u8::MAX && bytes.peek() == Some(&byte) {
+            bytes.next();
+            len += 1;
+        }
+        runs.push(len);
+        runs.push(byte);
+        if runs.len() > buffer.len() {
+            let mut raw = Vec::with_capacity(buffer.len() + 1);
+            raw.push(RAW);
+            raw.extend_from_slice(buffer);
+            return raw;
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_restore_every_plane() {
+        let sand: Vec<u8> = (0..600).map(|i| u8::try_from(i / 7).unwrap()).collect();
+        let water = alloc::vec![0xF0; 600];
+        let mut history = History::new();
+        history.push(&[&sand, &water]);
+
+        let (mut sand_out, mut water_out) = (alloc::vec![0; 600], alloc::vec![0; 600]);
+        assert!(history.restore(0, &mut [&mut sand_out, &mut water_out]));
+        assert_eq!((sand_out, water_out), (sand, water));
+        assert!(!history.restore(1, &mut []));
+    }
+
+    #[test]
+    fn noisy_planes_are_stored_raw() {
+        let noise: Vec<u8> = (0..600).map(|i| u8::try_from(i % 251).unwrap()).collect();
+        assert_eq!(encode(&noise).len(), noise.len() + 1);
+        assert!(encode(&[0; 600]).len() < 10);
+
+        let mut history = History::new();
+        history.push(&[&noise]);
+        let mut out = alloc::vec![0; 600];
+        assert!(history.restore(0, &mut [&mut out]));
+        assert_eq!(out, noise);
+    }
+}
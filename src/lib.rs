@@ -3,300 +3,1059 @@
 extern crate alloc;
 extern crate playdate as pd;
 
-use alloc::boxed::Box;
+#[cfg(feature = "dev-serial")]
+mod dev;
+mod export;
+mod history;
+pub mod rng;
+pub mod sim;
+mod sound;
+mod state;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
 use crankit_game_loop::{game_loop, Game, Playdate};
+use history::History;
 use pd::controls::buttons::PDButtonsExt;
-use pd::controls::peripherals::Buttons;
-use pd::display::Display;
+use pd::controls::peripherals::{Buttons, Crank};
+use pd::display::{Display, DisplayScale};
 use pd::graphics::BitmapDrawMode;
 use pd::sys::ffi::{LCD_COLUMNS, LCD_ROWS};
+use pd::sys::log::println;
+use pd::system::menu::{CheckMenuItem, OptionsMenuItem};
 use pd::system::System;
 use playdate::graphics::Graphics;
+use sim::{
+    calculate_screen_density, checker, count_sand, fill_rect, get_pixel, set_pixel, Emitter,
+    Material, PhysicsConfig, SimRegion, Simulation, BIT_MASKS, COLUMNS, EMITTER_RADIUS,
+    MAX_COHESION, MAX_WIND, PIXEL_WIDTH, ROWS,
+};
+pub use sim::{field_checksum, run_scenario, scatter, stamp_bitmap, surface_heights};
+use sound::Blip;
 
-const ROWS: usize = LCD_ROWS as usize;
-const COLUMNS: usize = 2 + (LCD_COLUMNS / 8) as usize;
-const PIXEL_WIDTH: usize = LCD_COLUMNS as usize;
-const BUFFER_SIZE: usize = COLUMNS * ROWS;
+// sim.rs spells out the LCD size to stay free of pd, keep the two in step
+const _: () = assert!(ROWS == LCD_ROWS as usize && PIXEL_WIDTH == LCD_COLUMNS as usize);
 
-// Pre-computed lookup tables for ultra-fast bit operations
-static BIT_MASKS: [u8; 8] = [0x80, 0x40, 0x20, 0x10, 0x08, 0x04, 0x02, 0x01];
-static INV_BIT_MASKS: [u8; 8] = [0x7F, 0xBF, 0xDF, 0xEF, 0xF7, 0xFB, 0xFD, 0xFE];
+// High-contrast view: grains on the edge of a pile stay solid while grains
+// fully surrounded by sand get a checkerboard, so the outline stands out
+fn outline_row(buffer: &[u8], y: usize, out: &mut [u8]) {
+    let row = &buffer[y * COLUMNS..(y + 1) * COLUMNS];
+    let checker = checker(y);
 
-fn clear_buffer(buffer: &mut [u8]) {
-    // Safe and complete clearing of buffer
-    for f in buffer.iter_mut().take(BUFFER_SIZE) {
-        *f = 0;
+    for byte_idx in 0..COLUMNS {
+        let cur = row[byte_idx];
+        if cur == 0 {
+            out[byte_idx] = 0;
+            continue;
+        }
+
+        let up = if y > 0 {
+            buffer[(y - 1) * COLUMNS + byte_idx]
+        } else {
+            0
+        };
+        let down = if y < ROWS - 1 {
+            buffer[(y + 1) * COLUMNS + byte_idx]
+        } else {
+            0
+        };
+        let prev = if byte_idx > 0 { row[byte_idx - 1] } else { 0 };
+        let next = if byte_idx < COLUMNS - 1 {
+            row[byte_idx + 1]
+        } else {
+            0
+        };
+
+        // Neighbours shifted so each bit lines up with the pixel it borders
+        let left = (cur >> 1) | (prev << 7);
+        let right = (cur << 1) | (next >> 7);
+
+        let interior = cur & up & down & left & right;
+        out[byte_idx] = (cur & !interior) | (interior & checker);
     }
 }
 
-#[inline(always)]
-fn get_pixel(buffer: &[u8], x: usize, y: usize) -> bool {
-    if x >= PIXEL_WIDTH || y >= ROWS {
-        return false;
+fn grow_changed_rows(changed_rows: &[bool; ROWS]) -> [bool; ROWS] {
+    let mut grown = *changed_rows;
+    for (y, _) in changed_rows.iter().enumerate().filter(|(_, &c)| c) {
+        if y > 0 {
+            grown[y - 1] = true;
+        }
+        if y < ROWS - 1 {
+            grown[y + 1] = true;
+        }
     }
-    let index = y * COLUMNS + (x >> 3);
-    (buffer[index] & BIT_MASKS[x & 7]) != 0
+    grown
 }
 
-#[inline(always)]
-fn set_pixel(buffer: &mut [u8], x: usize, y: usize, value: bool) {
-    if x >= PIXEL_WIDTH || y >= ROWS {
-        return;
+fn update_screen_efficiently(changed_rows: &[bool; ROWS]) {
+    let graphics = Graphics::Cached();
+    let mut batch_start: Option<usize> = None;
+
+    for (y, &changed) in changed_rows.iter().enumerate() {
+        if changed && batch_start.is_none() {
+            batch_start = Some(y);
+        } else if !changed && batch_start.is_some() {
+            graphics.mark_updated_rows(batch_start.unwrap() as i32, y as i32);
+            batch_start = None;
+        }
     }
-    let index = y * COLUMNS + (x >> 3);
-    let bit_idx = x & 7;
-    if value {
-        buffer[index] |= BIT_MASKS[bit_idx];
-    } else {
-        buffer[index] &= INV_BIT_MASKS[bit_idx];
+
+    if let Some(start) = batch_start {
+        graphics.mark_updated_rows(start as i32, ROWS as i32);
     }
 }
 
-// Simple falling sand physics
-#[inline]
-fn update_pixel(buffer: &mut [u8], x: usize, y: usize) -> bool {
-    if y >= ROWS - 1 || !get_pixel(buffer, x, y) {
-        return false;
+// Intro screen layout: (text, x, y), first line is the title
+const INTRO_LINES: &[(&str, i32, i32)] = &[
+    ("FALLING SAND", 120, 60),
+    ("Press any button to start", 80, 90),
+    ("A: Drop sand  B: Clear", 90, 120),
+    ("Arrows: Move cursor", 95, 140),
+    ("Hold B + A: Suction  Left + A: Emitter", 35, 160),
+    ("Hold A + Crank: Brush size", 75, 180),
+    ("Right + A: Brush shape", 90, 200),
+    ("Up + A: Material  Down + A: Eraser", 50, 220),
+];
+
+fn draw_intro() {
+    let graphics = Graphics::Cached();
+    let _ = graphics.set_draw_mode(BitmapDrawMode::kDrawModeFillWhite);
+    for &(text, x, y) in INTRO_LINES {
+        graphics.draw_text(text, x, y).unwrap();
     }
+}
 
-    // Try to move down
-    let can_move_down = !get_pixel(buffer, x, y + 1);
-    if can_move_down {
-        set_pixel(buffer, x, y, false);
-        set_pixel(buffer, x, y + 1, true);
-        return true;
+// Starting brush size, also the margin the cursor keeps from the edges
+const SAND_BRUSH_SIZE: usize = 5;
+// Brush sizes reachable with the crank and degrees of crank per pixel
+const MIN_BRUSH_SIZE: usize = 1;
+const MAX_BRUSH_SIZE: usize = 25;
+const BRUSH_CRANK_STEP: f32 = 20.0;
+const CURSOR_START: (usize, usize) = (PIXEL_WIDTH / 2, ROWS / 4);
+
+// Each material other than sand has its own plane, so each can be cleared
+// on its own
+fn clear_material(game: &mut FallingSand, which: Material) {
+    let sim = &mut game.sim;
+    match which {
+        Material::Sand => {
+            let others = sim.water.iter().zip(sim.ash.iter()).zip(sim.stone.iter());
+            for (cell, ((&water, &ash), &stone)) in sim.buffer.iter_mut().zip(others) {
+                *cell &= water | ash | stone;
+            }
+        }
+        Material::Water => {
+            for (cell, water) in sim.buffer.iter_mut().zip(sim.water.iter_mut()) {
+                *cell &= !*water;
+                *water = 0;
+            }
+        }
+        Material::Ash => {
+            for (cell, ash) in sim.buffer.iter_mut().zip(sim.ash.iter_mut()) {
+                *cell &= !*ash;
+                *ash = 0;
+            }
+        }
+        Material::Stone => {
+            for (cell, stone) in sim.buffer.iter_mut().zip(sim.stone.iter_mut()) {
+                *cell &= !*stone;
+                *stone = 0;
+            }
+        }
     }
+    sim.invalidate();
+}
 
-    // Try to move down-left
-    let can_move_left = x > 0 && !get_pixel(buffer, x - 1, y + 1);
-    if can_move_left {
-        set_pixel(buffer, x, y, false);
-        set_pixel(buffer, x - 1, y + 1, true);
-        return true;
+// Turning the crank while pouring grows or shrinks the brush
+fn adjust_brush_size(game: &mut FallingSand) {
+    game.crank_accum += Crank::Cached().change();
+    let steps = crank_steps(&mut game.crank_accum, BRUSH_CRANK_STEP);
+    game.brush_size = game
+        .brush_size
+        .saturating_add_signed(steps as isize)
+        .clamp(MIN_BRUSH_SIZE, MAX_BRUSH_SIZE);
+}
+
+// Whole `step`s of crank travel in `accum`, taken out of it. Negative when
+// turned backwards.
+fn crank_steps(accum: &mut f32, step: f32) -> i32 {
+    let mut steps = 0;
+    while *accum >= step {
+        *accum -= step;
+        steps += 1;
+    }
+    while *accum <= -step {
+        *accum += step;
+        steps -= 1;
     }
+    steps
+}
 
-    // Try to move down-right
-    let can_move_right = x < PIXEL_WIDTH - 1 && !get_pixel(buffer, x + 1, y + 1);
-    if can_move_right {
-        set_pixel(buffer, x, y, false);
-        set_pixel(buffer, x + 1, y + 1, true);
-        return true;
+// Degrees of crank per point of wind
+const WIND_CRANK_STEP: f32 = 30.0;
+
+// Turning the crank with no button held sets the wind
+fn adjust_wind(game: &mut FallingSand) {
+    game.crank_accum += Crank::Cached().change();
+    let steps = crank_steps(&mut game.crank_accum, WIND_CRANK_STEP);
+    let physics = &mut game.sim.physics;
+    let wind = (i32::from(physics.wind) + steps).clamp(-i32::from(MAX_WIND), i32::from(MAX_WIND));
+    physics.wind = i8::try_from(wind).unwrap();
+}
+
+// Degrees of crank per point of cohesion
+const COHESION_CRANK_STEP: f32 = 5.0;
+
+// Turning the crank while B is held makes the sand stickier or looser
+fn adjust_cohesion(game: &mut FallingSand) {
+    let change = Crank::Cached().change();
+    if change != 0.0 {
+        game.b_modifier_used = true;
     }
 
-    false
+    game.crank_accum += change;
+    let steps = crank_steps(&mut game.crank_accum, COHESION_CRANK_STEP);
+    let physics = &mut game.sim.physics;
+    let cohesion = (i32::from(physics.cohesion) + steps).clamp(0, i32::from(MAX_COHESION));
+    physics.cohesion = u8::try_from(cohesion).unwrap();
 }
 
-fn update_optimized(buffer: &mut [u8], changed_rows: &mut [bool; ROWS], skip_pattern: usize) {
-    for y in (0..ROWS - 1).rev() {
-        if y % skip_pattern != 0 {
-            continue;
-        }
+#[derive(Clone, Copy, PartialEq)]
+enum BrushShape {
+    Square,
+    Circle,
+}
 
-        let row_start = y * COLUMNS;
-        let mut row_changed = false;
+// Spray that fills every cell of the brush
+const SOLID_SPRAY: u8 = 100;
 
-        for byte_idx in 0..COLUMNS {
-            let byte_val = buffer[row_start + byte_idx];
-            if byte_val == 0 {
-                continue;
-            }
+// Sprays the Mode menu steps through, thinning out before going solid again
+const SPRAY_STEPS: [u8; 4] = [SOLID_SPRAY, 50, 25, 10];
 
-            let base_x = byte_idx << 3;
+fn next_spray(spray: u8) -> u8 {
+    SPRAY_STEPS
+        .into_iter()
+        .find(|&step| step < spray)
+        .unwrap_or(SOLID_SPRAY)
+}
 
-            // Unrolled bit processing for maximum performance
-            if (byte_val & 0x80) != 0 && update_pixel(buffer, base_x, y) {
-                row_changed = true;
-            }
-            if (byte_val & 0x40) != 0 && update_pixel(buffer, base_x + 1, y) {
-                row_changed = true;
-            }
-            if (byte_val & 0x20) != 0 && update_pixel(buffer, base_x + 2, y) {
-                row_changed = true;
-            }
-            if (byte_val & 0x10) != 0 && update_pixel(buffer, base_x + 3, y) {
-                row_changed = true;
-            }
-            if (byte_val & 0x08) != 0 && update_pixel(buffer, base_x + 4, y) {
-                row_changed = true;
-            }
-            if (byte_val & 0x04) != 0 && update_pixel(buffer, base_x + 5, y) {
-                row_changed = true;
-            }
-            if (byte_val & 0x02) != 0 && update_pixel(buffer, base_x + 6, y) {
-                row_changed = true;
+// Fill a size x size brush centred on (cx, cy), the circle keeps only the
+// cells within size / 2 of the centre. Nothing is drawn outside the region.
+// Without a material the brush empties the cells instead. Each cell is only
+// touched with a `spray` percent chance, SOLID_SPRAY covers all of them.
+fn stamp_brush(
+    sim: &mut Simulation,
+    (cx, cy): (usize, usize),
+    size: usize,
+    shape: BrushShape,
+    material: Option<Material>,
+    spray: u8,
+    changed_rows: &mut [bool; ROWS],
+) {
+    let region = sim.physics.region;
+    let half_size = size / 2;
+    for i in 0..size {
+        for j in 0..size {
+            let (dx, dy) = (i.abs_diff(half_size), j.abs_diff(half_size));
+            if shape == BrushShape::Circle && dx * dx + dy * dy > half_size * half_size {
+                continue;
             }
-            if (byte_val & 0x01) != 0 && update_pixel(buffer, base_x + 7, y) {
-                row_changed = true;
+
+            // Cells left of or above the screen edge are skipped, not wrapped
+            let (Some(x), Some(y)) = (
+                (cx + i).checked_sub(half_size),
+                (cy + j).checked_sub(half_size),
+            ) else {
+                continue;
+            };
+            if region.contains(x, y) && sim.chance(spray) {
+                sim.set_cell(x, y, material);
+                changed_rows[y] = true;
+                // Grains resting above an erased cell can fall into it
+                if material.is_none() {
+                    changed_rows[y.saturating_sub(1)] = true;
+                }
             }
         }
+    }
+}
 
-        if row_changed {
-            changed_rows[y] = true;
-            if y > 0 {
-                changed_rows[y - 1] = true;
-            }
-            if y < ROWS - 1 {
-                changed_rows[y + 1] = true;
+fn stamp_sand(game: &mut FallingSand, changed_rows: &mut [bool; ROWS]) {
+    stamp_brush(
+        &mut game.sim,
+        (game.position_x, game.position_y),
+        game.brush_size,
+        game.brush_shape,
+        (!game.erasing).then_some(game.brush_material),
+        game.spray,
+        changed_rows,
+    );
+}
+
+// In Box mode the first press of A marks a corner and the second fills the
+// box between it and the cursor with the brush material, or empties it when
+// erasing. Only the part inside the region is touched.
+fn place_box_corner(game: &mut FallingSand, changed_rows: &mut [bool; ROWS]) {
+    let cursor = (game.position_x, game.position_y);
+    let Some(corner) = game.box_corner.take() else {
+        game.box_corner = Some(cursor);
+        return;
+    };
+
+    let region = game.sim.physics.region;
+    let x0 = corner.0.min(cursor.0).max(region.x0);
+    let y0 = corner.1.min(cursor.1).max(region.y0);
+    let x1 = (corner.0.max(cursor.0) + 1).min(region.x1);
+    let y1 = (corner.1.max(cursor.1) + 1).min(region.y1);
+    if x0 >= x1 || y0 >= y1 {
+        return;
+    }
+
+    let material = (!game.erasing).then_some(game.brush_material);
+    let sim = &mut game.sim;
+    fill_rect(&mut *sim.buffer, x0, y0, x1, y1, material.is_some());
+    let water = material == Some(Material::Water);
+    let ash = material == Some(Material::Ash);
+    let stone = material == Some(Material::Stone);
+    fill_rect(&mut *sim.water, x0, y0, x1, y1, water);
+    fill_rect(&mut *sim.ash, x0, y0, x1, y1, ash);
+    fill_rect(&mut *sim.stone, x0, y0, x1, y1, stone);
+    // The row above may fall into an emptied box
+    for changed in &mut changed_rows[y0.saturating_sub(1)..y1] {
+        *changed = true;
+    }
+}
+
+// Grains an emitter tries to pour each frame
+const EMITTER_RATE: u8 = 2;
+
+// Drop an emitter at the cursor, or pick up the one already there
+fn toggle_emitter(game: &mut FallingSand) {
+    let (x, y) = (game.position_x, game.position_y);
+    let emitters = &mut game.sim.emitters;
+    let near = |e: &Emitter| x.abs_diff(e.x) <= EMITTER_RADIUS && y.abs_diff(e.y) <= EMITTER_RADIUS;
+    if let Some(index) = emitters.iter().position(near) {
+        emitters.remove(index);
+        // Take its marker off the screen
+        game.needs_redraw = true;
+    } else {
+        emitters.push(Emitter {
+            x,
+            y,
+            rate: EMITTER_RATE,
+        });
+    }
+}
+
+// Suction removes the closest grain within this many pixels of the cursor
+const SUCTION_RADIUS: usize = 12;
+
+// Walk square rings outwards from the cursor and remove the first grain found
+fn suck_nearest_grain(game: &mut FallingSand, changed_rows: &mut [bool; ROWS]) {
+    let (cx, cy) = (game.position_x, game.position_y);
+    for r in 0..=SUCTION_RADIUS {
+        for y in cy.saturating_sub(r)..=cy + r {
+            for x in cx.saturating_sub(r)..=cx + r {
+                let on_ring = x.abs_diff(cx) == r || y.abs_diff(cy) == r;
+                if !on_ring
+                    || !game.sim.physics.region.contains(x, y)
+                    || !get_pixel(&*game.sim.buffer, x, y)
+                {
+                    continue;
+                }
+
+                game.sim.set_cell(x, y, None);
+                // The row above may now have room to fall into
+                changed_rows[y] = true;
+                changed_rows[y.saturating_sub(1)] = true;
+                return;
             }
         }
     }
 }
 
-fn calculate_screen_density(buffer: &[u8]) -> u8 {
-    let mut pixel_count = 0u32;
-    for i in (0..buffer.len()).step_by(8) {
-        if buffer[i] != 0 {
-            pixel_count += buffer[i].count_ones();
+// Ambient demo phases as (pour every N frames, viscosity), 0 pours nothing.
+// After the last phase the screen is cleared and the cycle restarts.
+const DEMO_PHASES: [(u32, u8); 5] = [(3, 0), (1, 0), (2, 12), (1, 40), (0, 0)];
+const DEMO_PHASE_FRAMES: u32 = REFRESH_RATE * 40; // 40 seconds
+
+fn run_demo(game: &mut FallingSand, changed_rows: &mut [bool; ROWS]) {
+    let cycle_frames = DEMO_PHASE_FRAMES * DEMO_PHASES.len() as u32;
+    if game.demo_frame > 0 && game.demo_frame % cycle_frames == 0 {
+        for material in Material::ALL {
+            clear_material(game, material);
+        }
+        *changed_rows = [true; ROWS];
+    }
+
+    let (pour_every, viscosity) =
+        DEMO_PHASES[(game.demo_frame / DEMO_PHASE_FRAMES) as usize % DEMO_PHASES.len()];
+    game.sim.physics.viscosity = viscosity;
+
+    // Sweep the pour point back and forth near the top of the screen
+    let span = (PIXEL_WIDTH - 2 * SAND_BRUSH_SIZE) as u32;
+    let sweep = game.demo_frame % (2 * span);
+    let offset = if sweep < span {
+        sweep
+    } else {
+        2 * span - sweep
+    };
+    game.position_x = SAND_BRUSH_SIZE + offset as usize;
+    game.position_y = ROWS / 8;
+
+    if pour_every != 0 && game.demo_frame % pour_every == 0 {
+        stamp_sand(game, changed_rows);
+    }
+
+    game.demo_frame += 1;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Tool {
+    None,
+    Pour,
+    Suction,
+    Cohesion,
+    CycleMaterial,
+    ToggleErase,
+    ToggleEmitter,
+    ToggleShape,
+}
+
+// Direction held down when A was pushed, it picks a brush setting instead of
+// moving the cursor until A is released
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Modifier {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+// Held buttons to the tool they use, the most specific combination wins.
+// B also clears on release, unless it was used as a modifier meanwhile.
+fn resolve_tool(a: bool, b: bool, modifier: Option<Modifier>) -> Tool {
+    match (a, b, modifier) {
+        (true, true, _) => Tool::Suction,
+        (true, false, Some(Modifier::Up)) => Tool::CycleMaterial,
+        (true, false, Some(Modifier::Down)) => Tool::ToggleErase,
+        (true, false, Some(Modifier::Left)) => Tool::ToggleEmitter,
+        (true, false, Some(Modifier::Right)) => Tool::ToggleShape,
+        (true, false, None) => Tool::Pour,
+        (false, true, _) => Tool::Cohesion,
+        (false, false, _) => Tool::None,
+    }
+}
+
+// Settings picked with a direction change once, when A goes down
+fn push_a(game: &mut FallingSand, tool: Tool, changed_rows: &mut [bool; ROWS]) {
+    match tool {
+        Tool::CycleMaterial => {
+            game.brush_material = match game.brush_material {
+                Material::Sand => Material::Water,
+                Material::Water => Material::Ash,
+                Material::Ash | Material::Stone => Material::Sand,
+            };
+        }
+        Tool::ToggleErase => game.erasing = !game.erasing,
+        Tool::ToggleEmitter => toggle_emitter(game),
+        Tool::ToggleShape => {
+            game.brush_shape = match game.brush_shape {
+                BrushShape::Square => BrushShape::Circle,
+                BrushShape::Circle => BrushShape::Square,
+            };
         }
+        _ if game.mode == Mode::Box => place_box_corner(game, changed_rows),
+        _ => {}
     }
-    ((pixel_count * 100) / ((buffer.len() / 8) * 8) as u32).min(100) as u8
 }
 
-fn update_screen_efficiently(changed_rows: &[bool; ROWS]) {
+fn clear_screen(game: &mut FallingSand, frame: &mut [u8]) {
+    for material in Material::ALL {
+        clear_material(game, material);
+    }
+
+    // Clear frame buffer and mark all rows for update
+    for f in frame.iter_mut() {
+        *f = 0;
+    }
     let graphics = Graphics::Cached();
-    let mut batch_start: Option<usize> = None;
+    graphics.mark_updated_rows(0, LCD_ROWS as i32);
 
-    for (y, &changed) in changed_rows.iter().enumerate() {
-        if changed && batch_start.is_none() {
-            batch_start = Some(y);
-        } else if !changed && batch_start.is_some() {
-            graphics.mark_updated_rows(batch_start.unwrap() as i32, y as i32);
-            batch_start = None;
-        }
+    if !game.started {
+        draw_intro();
     }
 
-    if let Some(start) = batch_start {
-        graphics.mark_updated_rows(start as i32, ROWS as i32);
+    game.screen_density = 0;
+    game.grain_count = 0;
+    game.density_samples = 0;
+}
+
+// Milliseconds spent in each phase of the last simulated frame
+#[derive(Clone, Copy, Default)]
+struct PhaseTimes {
+    physics: f32,
+    copy: f32,
+    mark: f32,
+}
+
+// Milliseconds since the previous lap, skipped entirely when not profiling
+fn lap(enabled: bool) -> f32 {
+    if !enabled {
+        return 0.0;
     }
+    let system = System::Cached();
+    let elapsed = system.elapsed_time();
+    system.reset_elapsed_time();
+    elapsed * 1000.0
 }
 
-fn draw_intro() {
-    let graphics = Graphics::Cached();
-    let _ = graphics.set_draw_mode(BitmapDrawMode::kDrawModeFillWhite);
-    graphics.draw_text("FALLING SAND", 120, 100).unwrap();
-    graphics
-        .draw_text("Press any button to start", 80, 130)
-        .unwrap();
-    graphics
-        .draw_text("A: Drop sand  B: Clear", 90, 160)
-        .unwrap();
-    graphics.draw_text("Arrows: Move cursor", 95, 180).unwrap();
+// Outline around the cells the brush covers, as (x0, y0, x1, y1) with both
+// ends inclusive and clamped to the field
+fn cursor_rect((cx, cy): (usize, usize), size: usize) -> (usize, usize, usize, usize) {
+    let half_size = size / 2;
+    (
+        cx.saturating_sub(half_size + 1),
+        cy.saturating_sub(half_size + 1),
+        (cx + size - half_size).min(PIXEL_WIDTH - 1),
+        (cy + size - half_size).min(ROWS - 1),
+    )
 }
 
-const SAND_BRUSH_SIZE: usize = 5;
+// XOR the outline into the frame so it shows over sand and background alike
+fn draw_cursor(frame: &mut [u8], (x0, y0, x1, y1): (usize, usize, usize, usize)) {
+    let mut flip = |x: usize, y: usize| frame[y * COLUMNS + (x >> 3)] ^= BIT_MASKS[x & 7];
+    for x in x0..=x1 {
+        flip(x, y0);
+        flip(x, y1);
+    }
+    for y in y0 + 1..y1 {
+        flip(x0, y);
+        flip(x1, y);
+    }
+}
+
+// Copy the changed rows of the simulation to the screen
+fn present_frame(
+    game: &mut FallingSand,
+    frame: &mut [u8],
+    mut changed_rows: [bool; ROWS],
+    physics_ms: f32,
+) {
+    // The HUD text is drawn over these rows every frame, wipe it first
+    changed_rows[..HUD_ROWS].fill(true);
+
+    // Same for the cursor, both where it was last frame and where it is now
+    let cursor = game
+        .show_cursor
+        .then(|| cursor_rect((game.position_x, game.position_y), game.brush_size));
+    for (_, y0, _, y1) in [game.last_cursor, cursor].into_iter().flatten() {
+        changed_rows[y0..=y1].fill(true);
+    }
+    // Emitters are marked with a small box drawn the same way
+    let markers: Vec<_> = game
+        .sim
+        .emitters
+        .iter()
+        .map(|e| cursor_rect((e.x, e.y), 2 * EMITTER_RADIUS + 1))
+        .collect();
+    for &(_, y0, _, y1) in &markers {
+        changed_rows[y0..=y1].fill(true);
+    }
+
+    // Copy logic buffer to frame buffer for rendering
+    if game.outline {
+        // An outlined row also depends on the rows above and below it
+        changed_rows = grow_changed_rows(&changed_rows);
+        game.copy_outlined_to_frame(frame, &changed_rows);
+    } else {
+        game.copy_logic_to_frame(frame, &changed_rows);
+    }
+
+    for rect in markers.into_iter().chain(cursor) {
+        draw_cursor(frame, rect);
+    }
+    game.last_cursor = cursor;
+
+    let copy_ms = lap(game.profile);
+
+    update_screen_efficiently(&changed_rows);
+    game.phase_times = PhaseTimes {
+        physics: physics_ms,
+        copy: copy_ms,
+        mark: lap(game.profile),
+    };
+}
+
+// Frames per second the display aims for
+const REFRESH_RATE: u32 = 50;
+const FRAME_BUDGET_MS: u32 = 1000 / REFRESH_RATE;
+// How far past the budget a frame may run before the physics backs off
+const FRAME_SLACK_MS: u32 = 5;
+
+// Passes per frame and row skip pattern, scaled down as the screen fills up
+fn physics_steps(density: u8, max_substeps: u8) -> (u8, usize) {
+    let max_steps = max_substeps.max(1);
+    let fewer_steps = (max_steps - 1).max(1);
+    match density {
+        0..=25 => (max_steps, 1),    // Light density: full quality
+        26..=50 => (fewer_steps, 1), // Medium density: fewer steps
+        51..=75 => (fewer_steps, 2), // High density: skip every other row
+        _ => (1, 3),                 // Extreme density: minimal simulation
+    }
+}
+
+// Run this frame's physics passes, returns the milliseconds they took when
+// profiling
+fn simulate(game: &mut FallingSand, changed_rows: &mut [bool; ROWS]) -> f32 {
+    // Calculate density every 16 frames to reduce overhead
+    if game.frame_counter % 16 == 0 {
+        game.screen_density = calculate_screen_density(&*game.sim.buffer); // Fixed: dereference Box
+        game.grain_count = count_sand(&*game.sim.buffer);
+        game.record_density();
+    }
+
+    let now = unsafe { pd::sys::api!(system.getCurrentTimeMilliseconds)() };
+    let frame_ms = now.wrapping_sub(game.last_frame_ms);
+    game.last_frame_ms = now;
+
+    let (mut steps, skip_pattern) =
+        physics_steps(game.screen_density, game.sim.physics.max_substeps);
+    // A slow frame drops to one pass whatever the density, to stay responsive
+    if frame_ms > FRAME_BUDGET_MS + FRAME_SLACK_MS {
+        steps = 1;
+    }
+
+    // Paused sand stays put, the brush still draws
+    if game.mode == Mode::Pause {
+        return 0.0;
+    }
+
+    lap(game.profile);
+    let landings = game.sim.advance(steps, skip_pattern, changed_rows);
+    let physics_ms = lap(game.profile);
+    game.play_landing_sound(landings);
+
+    physics_ms
+}
 
 fn process_input(game: &mut FallingSand) {
     let frame = Graphics::Cached().get_frame().unwrap();
     let buttons = Buttons::Cached().get();
 
-    if buttons.current.any() {
+    if game.mode == Mode::Photo {
+        process_photo_mode(game, frame);
+        return;
+    }
+
+    if game.mode == Mode::History {
+        process_history_mode(game, frame);
+        return;
+    }
+
+    let mut changed_rows = [game.needs_redraw; ROWS];
+    game.needs_redraw = false;
+
+    if buttons.current.any() && !game.started {
         game.started = true;
+        // The intro text is still in the frame, redraw everything once
+        changed_rows = [true; ROWS];
     }
 
-    if buttons.current.a() {
-        let half_size = SAND_BRUSH_SIZE / 2;
-        for i in 0..SAND_BRUSH_SIZE {
-            for j in 0..SAND_BRUSH_SIZE {
-                let x = game.position_x + i - half_size;
-                let y = game.position_y + j - half_size;
-                if x < PIXEL_WIDTH && y < ROWS {
-                    set_pixel(&mut *game.logic_buffer, x, y, true); // Fixed: dereference Box
-                }
-            }
+    if game.mode == Mode::Demo {
+        run_demo(game, &mut changed_rows);
+    }
+
+    if buttons.pushed.a() {
+        game.a_modifier = if buttons.current.up() {
+            Some(Modifier::Up)
+        } else if buttons.current.down() {
+            Some(Modifier::Down)
+        } else if buttons.current.left() {
+            Some(Modifier::Left)
+        } else if buttons.current.right() {
+            Some(Modifier::Right)
+        } else {
+            None
+        };
+    }
+
+    let tool = resolve_tool(buttons.current.a(), buttons.current.b(), game.a_modifier);
+    if buttons.pushed.a() {
+        push_a(game, tool, &mut changed_rows);
+    }
+
+    match tool {
+        Tool::Suction => {
+            game.b_modifier_used = true;
+            suck_nearest_grain(game, &mut changed_rows);
         }
+        Tool::Cohesion => adjust_cohesion(game),
+        // Box mode draws with whole boxes instead
+        Tool::Pour if game.mode == Mode::Box => {}
+        Tool::Pour => {
+            adjust_brush_size(game);
+            stamp_sand(game, &mut changed_rows);
+        }
+        // The setting was changed when A was pushed, nothing to do while held
+        Tool::CycleMaterial | Tool::ToggleErase | Tool::ToggleEmitter | Tool::ToggleShape => {}
+        Tool::None => adjust_wind(game),
     }
 
-    // Arrow key movement
-    if buttons.current.left() && game.position_x > SAND_BRUSH_SIZE {
+    // Arrow key movement, kept inside the simulated region. The cursor stays
+    // put while a direction is held as a modifier.
+    let region = game.sim.physics.region;
+    let moving = !matches!(
+        tool,
+        Tool::CycleMaterial | Tool::ToggleErase | Tool::ToggleEmitter | Tool::ToggleShape
+    );
+    if moving && buttons.current.left() && game.position_x > region.x0 + SAND_BRUSH_SIZE {
         game.position_x -= 5;
     }
 
-    if buttons.current.right() && game.position_x < PIXEL_WIDTH - SAND_BRUSH_SIZE {
+    if moving && buttons.current.right() && game.position_x < region.x1 - SAND_BRUSH_SIZE {
         game.position_x += 5;
     }
 
-    if buttons.current.up() && game.position_y > SAND_BRUSH_SIZE {
+    if moving && buttons.current.up() && game.position_y > region.y0 + SAND_BRUSH_SIZE {
         game.position_y -= 5;
     }
 
-    if buttons.current.down() && game.position_y < ROWS - SAND_BRUSH_SIZE {
+    if moving && buttons.current.down() && game.position_y < region.y1 - SAND_BRUSH_SIZE {
         game.position_y += 5;
     }
 
-    if buttons.current.b() {
-        clear_buffer(&mut *game.logic_buffer); // Fixed: dereference Box
-
-        // Clear frame buffer and mark all rows for update
-        for f in frame.iter_mut() {
-            *f = 0;
-        }
-        let graphics = Graphics::Cached();
-        graphics.mark_updated_rows(0, LCD_ROWS as i32);
+    if buttons.released.a() || buttons.released.b() {
+        game.crank_accum = 0.0;
+    }
 
-        if !game.started {
-            draw_intro();
-        }
+    // B clears on release, unless it was held down as the suction modifier
+    let clear_requested = buttons.released.b() && !game.b_modifier_used;
+    if buttons.released.b() {
+        game.b_modifier_used = false;
+    }
 
-        game.screen_density = 0;
+    if clear_requested {
+        clear_screen(game, frame);
         return;
     }
 
     if !game.started {
         // Copy logic buffer to frame buffer
-        game.copy_logic_to_frame(frame);
+        game.sim.render_frame(frame);
         draw_intro();
         return;
     }
 
-    // Calculate density every 16 frames to reduce overhead
-    if game.frame_counter % 16 == 0 {
-        game.screen_density = calculate_screen_density(&*game.logic_buffer); // Fixed: dereference Box
+    let physics_ms = simulate(game, &mut changed_rows);
+
+    if game.mode != Mode::Pause && game.frame_counter % HISTORY_INTERVAL == 0 {
+        let sim = &game.sim;
+        game.history
+            .push(&[&*sim.buffer, &*sim.water, &*sim.ash, &*sim.stone]);
     }
 
-    let mut changed_rows = [false; ROWS];
+    present_frame(game, frame, changed_rows, physics_ms);
+    game.frame_counter += 1;
+}
 
-    // Performance scaling based on screen density
-    let (steps, skip_pattern) = match game.screen_density {
-        0..=25 => (3, 1),  // Light density: full quality
-        26..=50 => (2, 1), // Medium density: fewer steps
-        51..=75 => (2, 2), // High density: skip every other row
-        _ => (1, 3),       // Extreme density: minimal simulation
-    };
+// Photo mode shows a 2x zoomed quarter of the field, panned in whole bytes
+const PHOTO_WIDTH: usize = PIXEL_WIDTH / 2;
+const PHOTO_ROWS: usize = ROWS / 2;
+const PHOTO_PAN_STEP: usize = 8;
+
+// Big pixels mode runs the simulation in the part of the field a 2x scale
+// shows
+const BIG_REGION: SimRegion = SimRegion {
+    x0: 0,
+    y0: 0,
+    x1: PHOTO_WIDTH,
+    y1: PHOTO_ROWS,
+};
+
+// Hole in the middle of the floor that keeps a long session from filling up
+const FLOOR_DRAIN: SimRegion = SimRegion {
+    x0: PIXEL_WIDTH / 2 - 8,
+    y0: ROWS - 3,
+    x1: PIXEL_WIDTH / 2 + 8,
+    y1: ROWS,
+};
+
+// Overview of the whole field at a quarter of the size, a pixel is set when
+// any grain sits in its 4x4 block
+const THUMB_WIDTH: usize = PIXEL_WIDTH / 4;
+const THUMB_ROWS: usize = ROWS / 4;
 
-    for _ in 0..steps {
-        update_optimized(&mut *game.logic_buffer, &mut changed_rows, skip_pattern);
-        // Fixed: dereference Box
+fn draw_thumbnail(buffer: &[u8], out: &mut [u8], out_y: usize) {
+    for ty in 0..THUMB_ROWS {
+        let mut blocks = [0u8; COLUMNS];
+        for y in ty * 4..ty * 4 + 4 {
+            for (block, &byte) in blocks
+                .iter_mut()
+                .zip(&buffer[y * COLUMNS..(y + 1) * COLUMNS])
+            {
+                *block |= byte;
+            }
+        }
+
+        // Each source byte covers two thumbnail pixels
+        for tx in 0..THUMB_WIDTH {
+            let nibble = if tx & 1 == 0 { 0xF0 } else { 0x0F };
+            set_pixel(out, tx, out_y + ty, blocks[tx >> 1] & nibble != 0);
+        }
     }
+}
 
-    // Copy logic buffer to frame buffer for rendering
-    game.copy_logic_to_frame(frame);
+fn process_photo_mode(game: &mut FallingSand, frame: &mut [u8]) {
+    let buttons = Buttons::Cached().get();
 
-    update_screen_efficiently(&changed_rows);
-    game.frame_counter += 1;
+    if buttons.pushed.b() {
+        game.overview = !game.overview;
+    }
+
+    if buttons.current.left() {
+        game.pan_x = game.pan_x.saturating_sub(PHOTO_PAN_STEP);
+    }
+
+    if buttons.current.right() {
+        game.pan_x = (game.pan_x + PHOTO_PAN_STEP).min(PIXEL_WIDTH - PHOTO_WIDTH);
+    }
+
+    if buttons.current.up() {
+        game.pan_y = game.pan_y.saturating_sub(PHOTO_PAN_STEP);
+    }
+
+    if buttons.current.down() {
+        game.pan_y = (game.pan_y + PHOTO_PAN_STEP).min(ROWS - PHOTO_ROWS);
+    }
+
+    // set_scale only shows the top-left of the frame, so copy the panned
+    // window of the logic buffer there
+    let byte_x = game.pan_x >> 3;
+    let row_bytes = PHOTO_WIDTH >> 3;
+    for y in 0..PHOTO_ROWS {
+        let src = (game.pan_y + y) * COLUMNS + byte_x;
+        let dst = y * COLUMNS;
+        frame[dst..dst + row_bytes].copy_from_slice(&game.sim.buffer[src..src + row_bytes]);
+    }
+
+    if game.overview {
+        let out_y = PHOTO_ROWS - THUMB_ROWS;
+        draw_thumbnail(&*game.sim.buffer, frame, out_y);
+
+        // Frame the part of the field the zoomed view is showing
+        let (view_x, view_y) = (game.pan_x / 4, out_y + game.pan_y / 4);
+        let (view_w, view_h) = (PHOTO_WIDTH / 4, PHOTO_ROWS / 4);
+        for x in view_x..view_x + view_w {
+            set_pixel(frame, x, view_y, true);
+            set_pixel(frame, x, view_y + view_h - 1, true);
+        }
+        for y in view_y..view_y + view_h {
+            set_pixel(frame, view_x, y, true);
+            set_pixel(frame, view_x + view_w - 1, y, true);
+        }
+    }
+
+    Graphics::Cached().mark_updated_rows(0, PHOTO_ROWS as i32);
+}
+
+// Frames between history snapshots and degrees of crank per snapshot
+const HISTORY_INTERVAL: u32 = 5;
+const HISTORY_CRANK_STEP: f32 = 15.0;
+
+// The simulation is paused while the crank scrubs through the snapshots
+fn process_history_mode(game: &mut FallingSand, frame: &mut [u8]) {
+    let last = game.history.len().saturating_sub(1);
+    let previous = game.history_index;
+
+    game.crank_accum += Crank::Cached().change();
+    let steps = crank_steps(&mut game.crank_accum, HISTORY_CRANK_STEP);
+    game.history_index = game
+        .history_index
+        .saturating_add_signed(steps as isize)
+        .min(last);
+
+    // Drawn like the live field, so the materials stay apart while scrubbing
+    let Some(view) = game.history_view.as_mut() else {
+        return;
+    };
+    if (game.history_index != previous || game.needs_redraw)
+        && game.history.restore(
+            game.history_index,
+            &mut [
+                &mut *view.buffer,
+                &mut *view.water,
+                &mut *view.ash,
+                &mut *view.stone,
+            ],
+        )
+    {
+        view.render_frame(frame);
+        game.needs_redraw = false;
+        Graphics::Cached().mark_updated_rows(0, LCD_ROWS as i32);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Play,
+    Photo,
+    Demo,
+    Big,
+    History,
+    Pause,
+    Box,
+}
+
+// Options shown by the "Mode" system menu item, in Mode order. The last
+// entries are actions rather than modes, picking one runs it and goes back
+// to Play.
+const MODE_OPTIONS: [&str; 20] = [
+    "Play",
+    "Photo",
+    "Demo",
+    "Big",
+    "History",
+    "Pause",
+    "Box",
+    "Intro",
+    "Save",
+    "Load",
+    "Freeze",
+    "Shot",
+    "Clear sand",
+    "Clear water",
+    "Clear ash",
+    "Clear stone",
+    "Substeps",
+    "Wrap",
+    "Shade",
+    "Spray",
+];
+const INTRO_OPTION: i32 = 7;
+const SAVE_OPTION: i32 = 8;
+const LOAD_OPTION: i32 = 9;
+const FREEZE_OPTION: i32 = 10;
+const SHOT_OPTION: i32 = 11;
+const CLEAR_SAND_OPTION: i32 = 12;
+const CLEAR_WATER_OPTION: i32 = 13;
+const CLEAR_ASH_OPTION: i32 = 14;
+const CLEAR_STONE_OPTION: i32 = 15;
+const SUBSTEPS_OPTION: i32 = 16;
+const WRAP_OPTION: i32 = 17;
+const SHADE_OPTION: i32 = 18;
+const SPRAY_OPTION: i32 = 19;
+
+// Frames a menu action's notice stays in the HUD
+const NOTICE_FRAMES: u32 = REFRESH_RATE * 2;
+
+// Saved scene and screenshot in the game's data folder
+const SCENE_PATH: &str = "scene.sand";
+const SHOT_PATH: &str = "screenshot.pbm";
+
+impl Mode {
+    fn from_option(option: i32) -> Self {
+        match option {
+            1 => Mode::Photo,
+            2 => Mode::Demo,
+            3 => Mode::Big,
+            4 => Mode::History,
+            5 => Mode::Pause,
+            6 => Mode::Box,
+            _ => Mode::Play,
+        }
+    }
 }
 
 struct FallingSand {
     started: bool,
+    mode: Mode,
+    outline: bool,
+    // Photo mode shows the whole field in a corner
+    overview: bool,
+    needs_redraw: bool,
+    pan_x: usize,
+    pan_y: usize,
     position_x: usize,
     position_y: usize,
     frame_counter: u32,
     screen_density: u8,
-    logic_buffer: Box<[u8; BUFFER_SIZE]>, // Heap-allocated buffer to avoid stack overflow
+    // Grains on the field, refreshed along with screen_density
+    grain_count: u32,
+    // System time of the last simulated frame, in milliseconds
+    last_frame_ms: u32,
+    // Recent density samples for the sand clock, oldest overwritten first
+    density_history: [u8; DENSITY_HISTORY],
+    density_samples: usize,
+    sim: Simulation,
+    // Physics to restore when the demo ends
+    saved_physics: PhysicsConfig,
+    demo_frame: u32,
+    // B was used as the suction modifier since it was pressed
+    b_modifier_used: bool,
+    // Direction held when A was last pushed
+    a_modifier: Option<Modifier>,
+    sound_enabled: bool,
+    profile: bool,
+    phase_times: PhaseTimes,
+    next_sound_frame: u32,
+    history: History,
+    // Snapshot shown in history mode
+    history_index: usize,
+    // Field the snapshot is restored into for drawing, only kept in history
+    // mode
+    history_view: Option<Simulation>,
+    // Crank travel not yet turned into a history, brush size or cohesion step
+    crank_accum: f32,
+    brush_size: usize,
+    brush_shape: BrushShape,
+    brush_material: Material,
+    // The brush removes grains instead of pouring them
+    erasing: bool,
+    // First corner picked in Box mode, waiting for the second
+    box_corner: Option<(usize, usize)>,
+    // Percent of the brush cells each stamp fills, lower gives a sparse scatter
+    spray: u8,
+    show_cursor: bool,
+    // Outline drawn into the frame last frame, its rows need redrawing
+    last_cursor: Option<(usize, usize, usize, usize)>,
+    // Short message shown in the HUD after a menu action, and the frame it
+    // goes away on
+    notice: Option<(String, u32)>,
+    blip: Blip,
+    mode_menu: OptionsMenuItem,
+    outline_menu: CheckMenuItem,
+    sound_menu: CheckMenuItem,
 }
 
-impl FallingSand {
-    fn copy_logic_to_frame(&self, frame: &mut [u8]) {
-        // Copy logic buffer to frame buffer for rendering
-        let copy_len = BUFFER_SIZE.min(frame.len());
-        for i in 0..copy_len {
-            frame[i] = self.logic_buffer[i]; // Direct indexing works fine
-        }
-    }
-}
+// Landings needed in one frame to make a tick, frames between ticks and the
+// landing count that plays at full volume
+const LANDING_SOUND_THRESHOLD: u32 = 12;
+const LANDING_SOUND_COOLDOWN: u32 = 4;
+const LANDING_SOUND_FULL_VOLUME: u32 = 300;
 
-impl Game for FallingSand {
-    fn new(_playdate: &Playdate) -> Self {
-        Display::Cached().set_refresh_rate(50.0);
+// Rows covered by the FPS counter and the sand clock
+const HUD_ROWS: usize = 16;
+
+// Density samples (one every 16 frames) the sand clock fits its rate to, and
+// the density it counts as full: where the simulation drops to its lowest
+// quality
+const DENSITY_HISTORY: usize = 8;
+const SAND_CLOCK_FULL: u8 = 75;
+
+// Set to pin the simulation rng for reproducible runs, otherwise it is
+// seeded from the clock
+const SEED: Option<u64> = None;
+
+// Frames sand rests before it is drawn shaded, 5 seconds at REFRESH_RATE
+const SHADE_AGE: u16 = 250;
+
+impl FallingSand {
+    // Same as launching the game, with the simulation rng seeded from `seed`
+    fn with_seed(seed: u64) -> Self {
+        Display::Cached().set_refresh_rate(REFRESH_RATE as f32);
         let frame = Graphics::Cached().get_frame().unwrap();
 
         // Clear frame buffer
@@ -307,22 +1066,626 @@ impl Game for FallingSand {
         // Show intro
         draw_intro();
 
+        #[cfg(feature = "dev-serial")]
+        dev::install();
+
+        let mode_menu = OptionsMenuItem::new("Mode", MODE_OPTIONS, None, ()).unwrap();
+        let outline_menu = CheckMenuItem::new("Outline", false, None, ()).unwrap();
+        let sound_menu = CheckMenuItem::new("Sound", true, None, ()).unwrap();
+
+        let mut sim = Simulation::new(seed);
+        sim.drains.push(FLOOR_DRAIN);
+
         Self {
             started: false,
-            position_x: PIXEL_WIDTH / 2,
-            position_y: ROWS / 4,
+            mode: Mode::Play,
+            outline: false,
+            overview: false,
+            needs_redraw: false,
+            pan_x: 0,
+            pan_y: 0,
+            position_x: CURSOR_START.0,
+            position_y: CURSOR_START.1,
             frame_counter: 0,
             screen_density: 0,
-            logic_buffer: Box::new([0; BUFFER_SIZE]), // Heap allocation to avoid stack overflow
+            grain_count: 0,
+            last_frame_ms: 0,
+            density_history: [0; DENSITY_HISTORY],
+            density_samples: 0,
+            sim,
+            saved_physics: PhysicsConfig::default(),
+            demo_frame: 0,
+            b_modifier_used: false,
+            a_modifier: None,
+            sound_enabled: true,
+            profile: false,
+            phase_times: PhaseTimes::default(),
+            next_sound_frame: 0,
+            history: History::new(),
+            history_index: 0,
+            history_view: None,
+            crank_accum: 0.0,
+            brush_size: SAND_BRUSH_SIZE,
+            brush_shape: BrushShape::Square,
+            brush_material: Material::Sand,
+            erasing: false,
+            box_corner: None,
+            spray: SOLID_SPRAY,
+            show_cursor: true,
+            last_cursor: None,
+            notice: None,
+            blip: Blip::new(),
+            mode_menu,
+            outline_menu,
+            sound_menu,
+        }
+    }
+
+    fn record_density(&mut self) {
+        self.density_history[self.density_samples % DENSITY_HISTORY] = self.screen_density;
+        self.density_samples += 1;
+    }
+
+    // Linear extrapolation over the recorded samples, None while the field
+    // isn't filling up
+    fn seconds_until_full(&self) -> Option<u32> {
+        let count = self.density_samples.min(DENSITY_HISTORY);
+        if count < 2 {
+            return None;
+        }
+
+        let oldest = self.density_history[(self.density_samples - count) % DENSITY_HISTORY];
+        let newest = self.density_history[(self.density_samples - 1) % DENSITY_HISTORY];
+        if newest >= SAND_CLOCK_FULL {
+            return Some(0);
         }
+        if newest <= oldest {
+            return None;
+        }
+
+        let remaining = u32::from(SAND_CLOCK_FULL - newest);
+        let frames = (count as u32 - 1) * 16;
+        Some(remaining * frames / (u32::from(newest - oldest) * REFRESH_RATE))
+    }
+
+    fn sync_menu(&mut self) {
+        let option = self.mode_menu.selected_option();
+        if option >= INTRO_OPTION {
+            self.mode_menu.set_selected_option(0);
+            self.set_mode(Mode::Play);
+            match option {
+                INTRO_OPTION => self.reset(),
+                SAVE_OPTION => self.save_scene(),
+                LOAD_OPTION => self.load_scene(),
+                FREEZE_OPTION => self.needs_redraw |= self.sim.freeze(),
+                SHOT_OPTION => {
+                    if let Err(err) = export::export_frame(SHOT_PATH) {
+                        println(format!("could not save {SHOT_PATH}: {err:?}"));
+                    }
+                }
+                CLEAR_SAND_OPTION => self.clear_only(Material::Sand),
+                CLEAR_WATER_OPTION => self.clear_only(Material::Water),
+                CLEAR_ASH_OPTION => self.clear_only(Material::Ash),
+                CLEAR_STONE_OPTION => self.clear_only(Material::Stone),
+                SUBSTEPS_OPTION => {
+                    let substeps = self.sim.physics.max_substeps % sim::MAX_SUBSTEPS + 1;
+                    self.sim.physics.max_substeps = substeps;
+                    self.notify(format!("{substeps} substeps"));
+                }
+                WRAP_OPTION => {
+                    self.toggle_wrap();
+                    let state = if self.sim.physics.wrap_x { "on" } else { "off" };
+                    self.notify(format!("wrap {state}"));
+                }
+                SHADE_OPTION => {
+                    self.toggle_shade();
+                    let state = if self.sim.shade_age.is_some() {
+                        "on"
+                    } else {
+                        "off"
+                    };
+                    self.notify(format!("shade {state}"));
+                }
+                SPRAY_OPTION => {
+                    self.spray = next_spray(self.spray);
+                    self.notify(format!("spray {}%", self.spray));
+                }
+                _ => {}
+            }
+        }
+        self.set_mode(Mode::from_option(self.mode_menu.selected_option()));
+
+        self.sound_enabled = self.sound_menu.is_checked();
+
+        let outline = self.outline_menu.is_checked();
+        if outline != self.outline {
+            self.outline = outline;
+            self.needs_redraw = true;
+        }
+    }
+
+    // Show `text` in the HUD for a couple of seconds
+    fn notify(&mut self, text: String) {
+        self.notice = Some((text, self.frame_counter + NOTICE_FRAMES));
+    }
+
+    fn toggle_wrap(&mut self) {
+        self.sim.physics.wrap_x = !self.sim.physics.wrap_x;
+        // Grains resting against an edge may move now
+        self.sim.invalidate();
+    }
+
+    fn toggle_shade(&mut self) {
+        self.sim.shade_age = match self.sim.shade_age {
+            Some(_) => None,
+            None => Some(SHADE_AGE),
+        };
+        self.needs_redraw = true;
+    }
+
+    // Remove one material and leave the others where they are
+    fn clear_only(&mut self, material: Material) {
+        clear_material(self, material);
+        self.needs_redraw = true;
+    }
+
+    fn save_scene(&self) {
+        if let Err(err) = pd::fs::write(SCENE_PATH, state::serialize_state(&self.sim)) {
+            println(format!("could not save {SCENE_PATH}: {err:?}"));
+        }
+    }
+
+    fn load_scene(&mut self) {
+        let loaded = pd::fs::read(SCENE_PATH, true)
+            .map_err(|err| format!("{err:?}"))
+            .and_then(|blob| {
+                state::load_state(&mut self.sim, &blob).map_err(|err| format!("{err:?}"))
+            });
+        match loaded {
+            Ok(()) => {
+                // Show the scene straight away, even over the intro
+                self.started = true;
+                self.needs_redraw = true;
+            }
+            Err(err) => println(format!("could not load {SCENE_PATH}: {err}")),
+        }
+    }
+
+    fn play_landing_sound(&mut self, landings: u32) {
+        if !self.sound_enabled
+            || landings < LANDING_SOUND_THRESHOLD
+            || self.frame_counter < self.next_sound_frame
+        {
+            return;
+        }
+
+        self.blip.play(
+            landings.min(LANDING_SOUND_FULL_VOLUME) as f32 / LANDING_SOUND_FULL_VOLUME as f32,
+        );
+        self.next_sound_frame = self.frame_counter + LANDING_SOUND_COOLDOWN;
+    }
+
+    #[cfg(feature = "dev-serial")]
+    fn run_dev_command(&mut self) {
+        let Some(command) = dev::poll() else {
+            return;
+        };
+
+        match command {
+            dev::Command::Clear(None) => {
+                let frame = Graphics::Cached().get_frame().unwrap();
+                clear_screen(self, frame);
+            }
+            dev::Command::Clear(Some(material)) => self.clear_only(material),
+            dev::Command::Density => println(format!(
+                "density {}%, full in {:?}s",
+                calculate_screen_density(&*self.sim.buffer),
+                self.seconds_until_full()
+            )),
+            dev::Command::Dirty => println(format!("dirty {:?}", self.sim.take_dirty())),
+            dev::Command::Profile => self.profile = !self.profile,
+            dev::Command::Scatter { count, seed } => {
+                scatter(&mut *self.sim.buffer, count, &mut rng::seeded(seed));
+                self.needs_redraw = true;
+            }
+            dev::Command::Seed(seed) => self.sim.reseed(seed),
+            dev::Command::Wrap => self.toggle_wrap(),
+            dev::Command::Evaporate(ticks) => self.sim.physics.evaporation_ticks = ticks,
+            dev::Command::Cursor => self.show_cursor = !self.show_cursor,
+            dev::Command::Spray(percent) => self.spray = percent.clamp(1, SOLID_SPRAY),
+            dev::Command::Substeps(passes) => {
+                self.sim.physics.max_substeps = passes.clamp(1, sim::MAX_SUBSTEPS);
+            }
+            dev::Command::Shade => self.toggle_shade(),
+        }
+    }
+
+    // Back to the intro as if the game had just launched, the menu settings
+    // and physics are kept
+    fn reset(&mut self) {
+        self.started = false;
+        (self.position_x, self.position_y) = CURSOR_START;
+        self.frame_counter = 0;
+        self.next_sound_frame = 0;
+        self.demo_frame = 0;
+        self.b_modifier_used = false;
+        self.a_modifier = None;
+        self.history.clear();
+        self.sim.emitters.clear();
+
+        let frame = Graphics::Cached().get_frame().unwrap();
+        clear_screen(self, frame);
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        if self.mode == mode {
+            return;
+        }
+
+        // Leave the current mode
+        match self.mode {
+            Mode::Photo => {
+                Display::Cached().set_scale(DisplayScale::Normal);
+                self.needs_redraw = true;
+            }
+            Mode::Demo => self.sim.physics = self.saved_physics,
+            Mode::Big => {
+                self.sim.physics = self.saved_physics;
+                (self.position_x, self.position_y) = CURSOR_START;
+                Display::Cached().set_scale(DisplayScale::Normal);
+                self.needs_redraw = true;
+            }
+            Mode::History => {
+                self.history_view = None;
+                // Carry on from the snapshot that was scrubbed to. Left at
+                // the newest one, the live field is kept as it is, it is a
+                // few frames ahead of that snapshot.
+                let sim = &mut self.sim;
+                let scrubbed = self.history_index + 1 < self.history.len();
+                if scrubbed
+                    && self.history.restore(
+                        self.history_index,
+                        &mut [
+                            &mut *sim.buffer,
+                            &mut *sim.water,
+                            &mut *sim.ash,
+                            &mut *sim.stone,
+                        ],
+                    )
+                {
+                    sim.invalidate();
+                }
+                self.needs_redraw = true;
+            }
+            // Grains drawn while paused were never handed to the simulation
+            Mode::Pause => self.sim.invalidate(),
+            Mode::Box => self.box_corner = None,
+            Mode::Play => {}
+        }
+
+        // Enter the new one
+        match mode {
+            Mode::Photo => {
+                // Start the zoomed view around the cursor
+                let centered_x = self.position_x.saturating_sub(PHOTO_WIDTH / 2);
+                let centered_y = self.position_y.saturating_sub(PHOTO_ROWS / 2);
+                self.pan_x = (centered_x & !7).min(PIXEL_WIDTH - PHOTO_WIDTH);
+                self.pan_y = centered_y.min(ROWS - PHOTO_ROWS);
+                Display::Cached().set_scale(DisplayScale::Double);
+            }
+            Mode::Demo => {
+                self.saved_physics = self.sim.physics;
+                self.demo_frame = 0;
+                self.started = true;
+                self.needs_redraw = true;
+            }
+            Mode::Big => {
+                // Only the top-left quarter is shown at 2x, so only that is
+                // simulated. Sand outside it waits there untouched.
+                self.saved_physics = self.sim.physics;
+                self.sim.physics.region = BIG_REGION;
+                (self.position_x, self.position_y) = (BIG_REGION.x1 / 2, BIG_REGION.y1 / 4);
+                Display::Cached().set_scale(DisplayScale::Double);
+                self.started = true;
+                self.needs_redraw = true;
+            }
+            Mode::History => {
+                self.history_view = Some(Simulation::new(0));
+                self.history_index = self.history.len().saturating_sub(1);
+                self.crank_accum = 0.0;
+                self.needs_redraw = true;
+            }
+            Mode::Pause | Mode::Box | Mode::Play => {}
+        }
+
+        self.mode = mode;
+    }
+
+    fn copy_logic_to_frame(&self, frame: &mut [u8], changed_rows: &[bool; ROWS]) {
+        for (y, _) in changed_rows.iter().enumerate().filter(|(_, &c)| c) {
+            let row_start = (y * COLUMNS).min(frame.len());
+            let row_end = (row_start + COLUMNS).min(frame.len());
+            self.sim.render_row(y, &mut frame[row_start..row_end]);
+        }
+    }
+
+    fn copy_outlined_to_frame(&self, frame: &mut [u8], changed_rows: &[bool; ROWS]) {
+        for (y, _) in changed_rows.iter().enumerate().filter(|(_, &c)| c) {
+            let row_start = y * COLUMNS;
+            if row_start + COLUMNS > frame.len() {
+                break;
+            }
+            outline_row(
+                &*self.sim.buffer,
+                y,
+                &mut frame[row_start..row_start + COLUMNS],
+            );
+        }
+    }
+}
+
+impl Game for FallingSand {
+    fn new(_playdate: &Playdate) -> Self {
+        let seed = SEED.unwrap_or_else(|| u64::from(System::Cached().seconds_since_epoch()));
+        Self::with_seed(seed)
     }
 
     fn update(&mut self, _playdate: &Playdate) {
+        self.sync_menu();
+        #[cfg(feature = "dev-serial")]
+        self.run_dev_command();
         process_input(self);
 
-        // Draw UI elements on top of the game (after logic buffer copy)
-        System::Cached().draw_fps(0, 0);
+        // Draw UI elements on top of the game (after logic buffer copy),
+        // photo mode keeps the picture clean
+        if !matches!(self.mode, Mode::Photo | Mode::History) {
+            System::Cached().draw_fps(0, 0);
+
+            if self.profile {
+                let t = self.phase_times;
+                let _ = Graphics::Cached().draw_text(
+                    format!("sim {:.2} copy {:.2} mark {:.2}", t.physics, t.copy, t.mark),
+                    40,
+                    0,
+                );
+            } else if let Some((text, _)) = self
+                .notice
+                .as_ref()
+                .filter(|(_, until)| self.frame_counter < *until)
+            {
+                let _ = Graphics::Cached().draw_text(text, 160, 0);
+            } else if let Some((x, y)) = self.box_corner {
+                let _ = Graphics::Cached().draw_text(format!("box from {x},{y}"), 160, 0);
+            } else if self.started {
+                let _ =
+                    Graphics::Cached().draw_text(format!("{} grains", self.grain_count), 160, 0);
+            }
+
+            // Cohesion is only shown while B is held to set it
+            let buttons = Buttons::Cached().get();
+            if buttons.current.b() && !buttons.current.a() {
+                let _ = Graphics::Cached().draw_text(
+                    format!("cohesion {}", self.sim.physics.cohesion),
+                    60,
+                    0,
+                );
+            } else if self.sim.physics.wind != 0 {
+                let _ = Graphics::Cached().draw_text(
+                    format!("wind {:+}", self.sim.physics.wind),
+                    60,
+                    0,
+                );
+            }
+
+            if let Some(seconds) = self.seconds_until_full().filter(|_| self.started) {
+                let _ = Graphics::Cached().draw_text(format!("full in ~{seconds}s"), 300, 0);
+            }
+        }
     }
 }
 
 game_loop!(FallingSand);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brush_at_the_corner_does_not_wrap() {
+        let mut sim = Simulation::new(1);
+        let mut changed_rows = [false; ROWS];
+        let sand = Some(Material::Sand);
+        stamp_brush(
+            &mut sim,
+            (0, 0),
+            5,
+            BrushShape::Square,
+            sand,
+            SOLID_SPRAY,
+            &mut changed_rows,
+        );
+
+        // Only the quarter of the brush inside the field is drawn
+        assert_eq!(count_sand(&*sim.buffer), 9);
+        for (x, y) in [(0, 0), (2, 0), (0, 2), (2, 2)] {
+            assert!(get_pixel(&*sim.buffer, x, y));
+        }
+        for y in 0..ROWS {
+            assert!(!get_pixel(&*sim.buffer, PIXEL_WIDTH - 1, y));
+        }
+        assert!(changed_rows[..3].iter().all(|&c| c));
+        assert!(!changed_rows[3..].iter().any(|&c| c));
+
+        // Past the right edge nothing spills into the padding or the next row
+        sim.clear();
+        let corner = (PIXEL_WIDTH - 1, ROWS - 1);
+        stamp_brush(
+            &mut sim,
+            corner,
+            5,
+            BrushShape::Square,
+            sand,
+            SOLID_SPRAY,
+            &mut changed_rows,
+        );
+        assert_eq!(count_sand(&*sim.buffer), 9);
+        assert!(sim
+            .buffer
+            .chunks(COLUMNS)
+            .all(|row| row[PIXEL_WIDTH / 8..] == [0, 0]));
+    }
+
+    #[test]
+    fn held_buttons_resolve_to_one_tool() {
+        assert_eq!(resolve_tool(false, false, None), Tool::None);
+        assert_eq!(resolve_tool(true, false, None), Tool::Pour);
+        assert_eq!(resolve_tool(false, true, None), Tool::Cohesion);
+        // A held together with B sucks grains up instead of pouring
+        assert_eq!(resolve_tool(true, true, None), Tool::Suction);
+        assert_eq!(resolve_tool(true, true, Some(Modifier::Up)), Tool::Suction);
+
+        // A pushed with a direction held changes a setting and doesn't pour
+        assert_eq!(
+            resolve_tool(true, false, Some(Modifier::Up)),
+            Tool::CycleMaterial
+        );
+        assert_eq!(
+            resolve_tool(true, false, Some(Modifier::Down)),
+            Tool::ToggleErase
+        );
+        assert_eq!(
+            resolve_tool(true, false, Some(Modifier::Left)),
+            Tool::ToggleEmitter
+        );
+        assert_eq!(
+            resolve_tool(true, false, Some(Modifier::Right)),
+            Tool::ToggleShape
+        );
+        // Once A is up the direction is a plain arrow key again
+        assert_eq!(resolve_tool(false, false, Some(Modifier::Left)), Tool::None);
+    }
+
+    #[test]
+    fn circle_brush_covers_the_cells_within_its_radius() {
+        let mut sim = Simulation::new(1);
+        let mut changed_rows = [false; ROWS];
+        let centre = (PIXEL_WIDTH / 2, ROWS / 2);
+        let sand = Some(Material::Sand);
+        for (size, shape, cells) in [
+            (5, BrushShape::Square, 25),
+            (5, BrushShape::Circle, 13),
+            (9, BrushShape::Circle, 49),
+            (1, BrushShape::Circle, 1),
+        ] {
+            sim.clear();
+            stamp_brush(
+                &mut sim,
+                centre,
+                size,
+                shape,
+                sand,
+                SOLID_SPRAY,
+                &mut changed_rows,
+            );
+            assert_eq!(count_sand(&*sim.buffer), cells, "size {size}");
+            assert!(get_pixel(&*sim.buffer, centre.0, centre.1));
+        }
+    }
+
+    #[test]
+    fn erasing_a_stamp_leaves_nothing_behind() {
+        let empty = Simulation::new(1).fnv_hash_state();
+        let mut sim = Simulation::new(1);
+        let mut changed_rows = [false; ROWS];
+        let centre = (PIXEL_WIDTH / 2, ROWS / 2);
+        for material in Material::ALL {
+            for brush in [Some(material), None] {
+                stamp_brush(
+                    &mut sim,
+                    centre,
+                    7,
+                    BrushShape::Circle,
+                    brush,
+                    SOLID_SPRAY,
+                    &mut changed_rows,
+                );
+            }
+            // Every material plane is emptied along with the grain
+            assert_eq!(sim.fnv_hash_state(), empty, "{material:?}");
+        }
+    }
+
+    #[test]
+    fn physics_backs_off_at_each_density_step() {
+        for (density, steps) in [
+            (25, (3, 1)),
+            (26, (2, 1)),
+            (50, (2, 1)),
+            (51, (2, 2)),
+            (75, (2, 2)),
+            (76, (1, 3)),
+        ] {
+            assert_eq!(physics_steps(density, 3), steps, "density {density}");
+        }
+        // A single pass can't be cut down any further
+        assert_eq!(physics_steps(40, 1), (1, 1));
+        assert_eq!(physics_steps(0, 0), (1, 1));
+    }
+
+    #[test]
+    fn spray_fills_about_its_share_of_the_brush() {
+        let mut sim = Simulation::new(21);
+        let mut changed_rows = [false; ROWS];
+        let mut covered = 0;
+        // 20 stamps of a 21x21 square at 30 percent
+        for _ in 0..20 {
+            sim.clear();
+            stamp_brush(
+                &mut sim,
+                (PIXEL_WIDTH / 2, ROWS / 2),
+                21,
+                BrushShape::Square,
+                Some(Material::Sand),
+                30,
+                &mut changed_rows,
+            );
+            covered += count_sand(&*sim.buffer);
+        }
+        let percent = covered * 100 / (20 * 21 * 21);
+        assert!((27..=33).contains(&percent), "{percent} percent covered");
+    }
+
+    #[test]
+    fn spray_steps_cycle_back_to_solid() {
+        assert_eq!(next_spray(SOLID_SPRAY), 50);
+        assert_eq!(next_spray(50), 25);
+        assert_eq!(next_spray(25), 10);
+        assert_eq!(next_spray(10), SOLID_SPRAY);
+        // A dev command may have picked a spray between the steps
+        assert_eq!(next_spray(37), 25);
+        assert_eq!(next_spray(1), SOLID_SPRAY);
+    }
+
+    #[test]
+    fn cursor_outline_stays_on_the_field() {
+        // One pixel outside the brush on every side
+        assert_eq!(cursor_rect((100, 100), 5), (97, 97, 103, 103));
+        assert_eq!(cursor_rect((100, 100), 1), (99, 99, 101, 101));
+        assert_eq!(cursor_rect((0, 0), 5), (0, 0, 3, 3));
+        assert_eq!(
+            cursor_rect((PIXEL_WIDTH - 1, ROWS - 1), 5),
+            (PIXEL_WIDTH - 4, ROWS - 4, PIXEL_WIDTH - 1, ROWS - 1)
+        );
+    }
+
+    #[test]
+    fn crank_travel_turns_into_whole_steps() {
+        let mut accum = 50.0;
+        assert_eq!(crank_steps(&mut accum, 20.0), 2);
+        assert!((accum - 10.0).abs() < f32::EPSILON);
+        accum -= 55.0;
+        assert_eq!(crank_steps(&mut accum, 20.0), -2);
+        assert!((accum + 5.0).abs() < f32::EPSILON);
+        assert_eq!(crank_steps(&mut accum, 20.0), 0);
+    }
+}
@@ -0,0 +1,85 @@
+use rand::rngs::SmallRng;
+use rand::RngCore;
+
+// Source of random numbers for the simulation
+pub trait Rng {
+    fn next_u32(&mut self) -> u32;
+}
+
+impl Rng for SmallRng {
+    fn next_u32(&mut self) -> u32 {
+        RngCore::next_u32(self)
+    }
+}
+
+// Hand-rolled xorshift64, lighter than SmallRng on the device
+pub struct XorShift64 {
+    state: u64,
+}
+
+impl XorShift64 {
+    #[must_use]
+    pub fn seed_from_u64(seed: u64) -> Self {
+        // An all-zero state would only ever produce zeros
+        let state = if seed == 0 {
+            0x9E37_79B9_7F4A_7C15
+        } else {
+            seed
+        };
+        Self { state }
+    }
+}
+
+impl Rng for XorShift64 {
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        (x >> 32) as u32
+    }
+}
+
+// Generator used by the game, the "xorshift" feature swaps it at build time
+#[cfg(not(feature = "xorshift"))]
+pub type GameRng = SmallRng;
+#[cfg(feature = "xorshift")]
+pub type GameRng = XorShift64;
+
+#[must_use]
+pub fn seeded(seed: u64) -> GameRng {
+    #[cfg(not(feature = "xorshift"))]
+    use rand::SeedableRng;
+    GameRng::seed_from_u64(seed)
+}
+
+// Uniform-ish value in min..max, max must be greater than min
+pub fn rand_range(rng: &mut impl Rng, min: u32, max: u32) -> u32 {
+    min + rng.next_u32() % (max - min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xorshift_rand_range_is_roughly_uniform() {
+        let mut rng = XorShift64::seed_from_u64(123);
+        let mut buckets = [0u32; 10];
+        for _ in 0..100_000 {
+            buckets[rand_range(&mut rng, 0, 10) as usize] += 1;
+        }
+        // Each bucket within 5% of the 10000 it would get on average
+        for count in buckets {
+            assert!((9_500..=10_500).contains(&count), "{buckets:?}");
+        }
+    }
+
+    #[test]
+    fn xorshift_zero_seed_still_varies() {
+        let mut rng = XorShift64::seed_from_u64(0);
+        let first = rng.next_u32();
+        assert!((0..10).any(|_| rng.next_u32() != first));
+    }
+}
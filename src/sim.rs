@@ -0,0 +1,1819 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::rng::{self, rand_range, GameRng, Rng};
+
+// Everything the falling sand physics needs, free of the Playdate API so it
+// can be stepped on a host as well as on the device
+
+// Field size, the Playdate LCD written out so this module doesn't need pd
+pub const ROWS: usize = 240;
+pub const PIXEL_WIDTH: usize = 400;
+pub const COLUMNS: usize = 2 + PIXEL_WIDTH / 8;
+pub const BUFFER_SIZE: usize = COLUMNS * ROWS;
+pub const CELL_COUNT: usize = PIXEL_WIDTH * ROWS;
+
+// Pre-computed lookup tables for ultra-fast bit operations
+pub static BIT_MASKS: [u8; 8] = [0x80, 0x40, 0x20, 0x10, 0x08, 0x04, 0x02, 0x01];
+pub static INV_BIT_MASKS: [u8; 8] = [0x7F, 0xBF, 0xDF, 0xEF, 0xF7, 0xFB, 0xFD, 0xFE];
+
+pub fn clear_buffer(buffer: &mut [u8]) {
+    // Safe and complete clearing of buffer
+    for f in buffer.iter_mut().take(BUFFER_SIZE) {
+        *f = 0;
+    }
+}
+
+#[inline(always)]
+#[must_use]
+pub fn get_pixel(buffer: &[u8], x: usize, y: usize) -> bool {
+    if x >= PIXEL_WIDTH || y >= ROWS {
+        return false;
+    }
+    let index = y * COLUMNS + (x >> 3);
+    (buffer[index] & BIT_MASKS[x & 7]) != 0
+}
+
+#[inline(always)]
+pub fn set_pixel(buffer: &mut [u8], x: usize, y: usize, value: bool) {
+    if x >= PIXEL_WIDTH || y >= ROWS {
+        return;
+    }
+    let index = y * COLUMNS + (x >> 3);
+    let bit_idx = x & 7;
+    if value {
+        buffer[index] |= BIT_MASKS[bit_idx];
+    } else {
+        buffer[index] &= INV_BIT_MASKS[bit_idx];
+    }
+}
+
+// Drop `count` grains at random positions, a seeded rng gives the same field
+// every time. Grains landing on an occupied cell are not retried.
+pub fn scatter(buffer: &mut [u8], count: usize, rng: &mut impl Rng) {
+    for _ in 0..count {
+        let x = rng.next_u32() as usize % PIXEL_WIDTH;
+        let y = rng.next_u32() as usize % ROWS;
+        set_pixel(buffer, x, y, true);
+    }
+}
+
+// Rectangle the simulation runs in, exclusive on the right and bottom. Its
+// borders act as walls and sand outside it is left untouched.
+#[derive(Clone, Copy)]
+pub struct SimRegion {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl SimRegion {
+    pub const FULL: Self = Self {
+        x0: 0,
+        y0: 0,
+        x1: PIXEL_WIDTH,
+        y1: ROWS,
+    };
+
+    #[must_use]
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x0 && x < self.x1 && y >= self.y0 && y < self.y1
+    }
+
+    // Bits of byte `byte_idx` that fall inside the region's columns
+    fn column_mask(&self, byte_idx: usize) -> u8 {
+        let first_x = byte_idx << 3;
+        let mut mask = 0xFF;
+        for (bit, inv_mask) in INV_BIT_MASKS.iter().enumerate() {
+            let x = first_x + bit;
+            if x < self.x0 || x >= self.x1 {
+                mask &= inv_mask;
+            }
+        }
+        mask
+    }
+}
+
+impl Default for SimRegion {
+    fn default() -> Self {
+        Self::FULL
+    }
+}
+
+// What a grain is made of. Every material but sand has its own plane over
+// the occupied cells, a grain in none of them is sand.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Material {
+    Sand,
+    Water,
+    Ash,
+    // Frozen sand, never moves and holds up whatever lands on it
+    Stone,
+}
+
+impl Material {
+    pub const ALL: [Material; 4] = [
+        Material::Sand,
+        Material::Water,
+        Material::Ash,
+        Material::Stone,
+    ];
+
+    // A grain sinks through a lighter one below it by trading places
+    #[must_use]
+    pub fn density(self) -> u8 {
+        match self {
+            Material::Water => 1,
+            Material::Ash => 2,
+            Material::Sand => 3,
+            Material::Stone => u8::MAX,
+        }
+    }
+}
+
+// Bounding box of the cells that changed, both ends inclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirtyRegion {
+    pub min_x: usize,
+    pub max_x: usize,
+    pub min_y: usize,
+    pub max_y: usize,
+}
+
+impl DirtyRegion {
+    pub const FULL: Self = Self {
+        min_x: 0,
+        max_x: PIXEL_WIDTH - 1,
+        min_y: 0,
+        max_y: ROWS - 1,
+    };
+}
+
+// Stretch `dirty` to cover (x, y), starting a new box if there was none
+#[inline]
+fn grow_dirty(dirty: &mut Option<DirtyRegion>, x: usize, y: usize) {
+    *dirty = Some(match *dirty {
+        Some(rect) => DirtyRegion {
+            min_x: rect.min_x.min(x),
+            max_x: rect.max_x.max(x),
+            min_y: rect.min_y.min(y),
+            max_y: rect.max_y.max(y),
+        },
+        None => DirtyRegion {
+            min_x: x,
+            max_x: x,
+            min_y: y,
+            max_y: y,
+        },
+    });
+}
+
+// Tunable physics parameters
+#[derive(Clone, Copy)]
+pub struct PhysicsConfig {
+    pub region: SimRegion,
+    // Updates a grain must spend blocked below before it slides diagonally,
+    // 0 behaves like loose sand and higher values like thick mud
+    pub viscosity: u8,
+    // Most simulation passes per frame, the density heuristic scales down
+    // from here as the screen fills up
+    pub max_substeps: u8,
+    // Pick a side at random when both diagonals are free, instead of always
+    // trying down-left first
+    pub random_diagonal: bool,
+    // Percent chance a sand grain holds on instead of sliding diagonally,
+    // 0 gives the usual 45 degree piles and MAX_COHESION upright columns
+    pub cohesion: u8,
+    // Grains leaving one side of the region come back in on the other
+    pub wrap_x: bool,
+    // Frames a grain may rest in place before it evaporates, None keeps
+    // grains forever
+    pub evaporation_ticks: Option<u16>,
+    // Steady sideways wind, positive blows right. Each point out of MAX_WIND
+    // is a 10% chance for a grain to be blown one column downwind, whether
+    // it falls, slides or rests next to an empty cell.
+    pub wind: i8,
+}
+
+pub const MAX_COHESION: u8 = 100;
+pub const MAX_SUBSTEPS: u8 = 8;
+pub const MAX_WIND: i8 = 10;
+
+impl Default for PhysicsConfig {
+    fn default() -> Self {
+        Self {
+            region: SimRegion::FULL,
+            viscosity: 0,
+            max_substeps: 3,
+            random_diagonal: true,
+            cohesion: 0,
+            wrap_x: false,
+            evaporation_ticks: None,
+            wind: 0,
+        }
+    }
+}
+
+impl PhysicsConfig {
+    // Neighbouring columns inside the region, wrapping around its edges when
+    // wrap_x is on
+    #[inline]
+    fn left_of(&self, x: usize) -> Option<usize> {
+        if x > self.region.x0 {
+            Some(x - 1)
+        } else if self.wrap_x {
+            Some(self.region.x1 - 1)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn right_of(&self, x: usize) -> Option<usize> {
+        if x + 1 < self.region.x1 {
+            Some(x + 1)
+        } else if self.wrap_x {
+            Some(self.region.x0)
+        } else {
+            None
+        }
+    }
+}
+
+// How far along its row water looks for somewhere lower to flow to
+const FLOW_DISTANCE: usize = 16;
+
+// Grain updates allowed per frame before the remaining rows are deferred
+const UPDATE_BUDGET: u32 = 24_000;
+
+// State shared by every grain update of a frame
+struct Step<'a> {
+    buffer: &'a mut [u8],
+    water: &'a mut [u8],
+    ash: &'a mut [u8],
+    stone: &'a [u8],
+    // One blocked-update counter per cell, used by viscosity
+    waits: &'a mut [u8],
+    // Frames each grain has rested, used by evaporation
+    ages: &'a mut [u16],
+    config: PhysicsConfig,
+    rng: &'a mut GameRng,
+    // Grain updates left this frame
+    budget: u32,
+    // Set when a grain in the current row is still waiting to slide
+    busy: bool,
+    // Grains that came to rest on something
+    landings: u32,
+    // Scan right to left and prefer down-right, flipped every pass
+    mirror: bool,
+    // Cells changed so far
+    dirty: Option<DirtyRegion>,
+}
+
+impl Step<'_> {
+    // A grain has landed once there is nothing directly below it to fall into
+    #[inline]
+    fn has_landed(&self, x: usize, y: usize) -> bool {
+        y + 1 >= self.config.region.y1 || get_pixel(self.buffer, x, y + 1)
+    }
+
+    // Material of the grain at (x, y), which must be occupied
+    #[inline]
+    fn material(&self, x: usize, y: usize) -> Material {
+        if get_pixel(self.water, x, y) {
+            Material::Water
+        } else if get_pixel(self.ash, x, y) {
+            Material::Ash
+        } else if get_pixel(self.stone, x, y) {
+            Material::Stone
+        } else {
+            Material::Sand
+        }
+    }
+
+    #[inline]
+    fn set_material(&mut self, x: usize, y: usize, material: Material) {
+        set_pixel(self.water, x, y, material == Material::Water);
+        set_pixel(self.ash, x, y, material == Material::Ash);
+    }
+
+    #[inline]
+    fn move_grain(&mut self, x: usize, y: usize, to_x: usize, to_y: usize) {
+        let material = self.material(x, y);
+        set_pixel(self.buffer, x, y, false);
+        set_pixel(self.buffer, to_x, to_y, true);
+        set_pixel(self.water, x, y, false);
+        set_pixel(self.ash, x, y, false);
+        self.set_material(to_x, to_y, material);
+        self.waits[y * PIXEL_WIDTH + x] = 0;
+        self.waits[to_y * PIXEL_WIDTH + to_x] = 0;
+        self.ages[y * PIXEL_WIDTH + x] = 0;
+        self.ages[to_y * PIXEL_WIDTH + to_x] = 0;
+        grow_dirty(&mut self.dirty, x, y);
+        grow_dirty(&mut self.dirty, to_x, to_y);
+        if to_y > y && self.has_landed(to_x, to_y) {
+            self.landings += 1;
+        }
+    }
+
+    #[inline]
+    fn holds_on(&mut self) -> bool {
+        match self.config.cohesion {
+            0 => false,
+            MAX_COHESION.. => true,
+            cohesion => self.rng.next_u32() % u32::from(MAX_COHESION) < u32::from(cohesion),
+        }
+    }
+
+    #[inline]
+    fn left_of(&self, x: usize) -> Option<usize> {
+        self.config.left_of(x)
+    }
+
+    #[inline]
+    fn right_of(&self, x: usize) -> Option<usize> {
+        self.config.right_of(x)
+    }
+
+    // Rolls the wind's chance to blow a grain this update, never without wind
+    #[inline]
+    fn gust(&mut self) -> bool {
+        let wind = self.config.wind;
+        wind != 0
+            && self.rng.next_u32() % u32::from(MAX_WIND.unsigned_abs())
+                < u32::from(wind.unsigned_abs())
+    }
+
+    // The empty cell of row `y` next to `x` on the downwind side, if any
+    #[inline]
+    fn downwind(&self, x: usize, y: usize) -> Option<usize> {
+        let to_x = match self.config.wind.signum() {
+            1 => self.right_of(x),
+            -1 => self.left_of(x),
+            _ => None,
+        }?;
+        (!get_pixel(self.buffer, to_x, y)).then_some(to_x)
+    }
+
+    // Column a falling grain is blown to on its way into row `y`, if any
+    #[inline]
+    fn drift(&mut self, x: usize, y: usize) -> Option<usize> {
+        if !self.gust() {
+            return None;
+        }
+        self.downwind(x, y)
+    }
+
+    // Whether walking along row `y` from `x` with `next` reaches a cell with
+    // room below it within FLOW_DISTANCE empty cells
+    #[inline]
+    fn finds_drop(&self, mut x: usize, y: usize, next: fn(&Self, usize) -> Option<usize>) -> bool {
+        if y + 1 >= self.config.region.y1 {
+            return false;
+        }
+        for _ in 0..FLOW_DISTANCE {
+            let Some(to_x) = next(self, x).filter(|&to_x| !get_pixel(self.buffer, to_x, y)) else {
+                return false;
+            };
+            if !get_pixel(self.buffer, to_x, y + 1) {
+                return true;
+            }
+            x = to_x;
+        }
+        false
+    }
+
+    // The empty neighbouring cells of (x, y) on each side
+    #[inline]
+    fn free_sides(&self, x: usize, y: usize) -> (Option<usize>, Option<usize>) {
+        let free = |to_x: &usize| !get_pixel(self.buffer, *to_x, y);
+        (self.left_of(x).filter(free), self.right_of(x).filter(free))
+    }
+
+    // Column to move to given which sides are free, a fixed order would lean
+    // every pile the same way. Wind leans them downwind on purpose.
+    #[inline]
+    fn pick_side(&mut self, left: Option<usize>, right: Option<usize>) -> Option<usize> {
+        let go_left = if left.is_some() && right.is_some() {
+            if self.gust() {
+                self.config.wind < 0
+            } else if self.config.random_diagonal {
+                self.rng.next_u32() & 1 == 0
+            } else {
+                !self.mirror
+            }
+        } else {
+            left.is_some()
+        };
+
+        if go_left {
+            left
+        } else {
+            right
+        }
+    }
+
+    // The fixed-length bit loop gets unrolled by the compiler
+    #[inline]
+    fn update_byte(&mut self, byte_val: u8, base_x: usize, y: usize) -> bool {
+        let mut changed = false;
+        for k in 0..8 {
+            let bit = if self.mirror { 7 - k } else { k };
+            if (byte_val & BIT_MASKS[bit]) != 0 && self.update_pixel(base_x + bit, y) {
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    // Simple falling sand physics
+    #[inline]
+    fn update_pixel(&mut self, x: usize, y: usize) -> bool {
+        let region = self.config.region;
+        if !get_pixel(self.buffer, x, y) {
+            return false;
+        }
+        let material = self.material(x, y);
+        if material == Material::Stone {
+            return false;
+        }
+        let liquid = material == Material::Water;
+
+        if y + 1 < region.y1 {
+            // Try to move down
+            let can_move_down = !get_pixel(self.buffer, x, y + 1);
+            if can_move_down {
+                let to_x = self.drift(x, y + 1).unwrap_or(x);
+                self.move_grain(x, y, to_x, y + 1);
+                return true;
+            }
+
+            // Denser grains sink through lighter ones by trading places
+            let below = self.material(x, y + 1);
+            if material.density() > below.density() {
+                self.set_material(x, y, below);
+                self.set_material(x, y + 1, material);
+                self.waits[y * PIXEL_WIDTH + x] = 0;
+                self.waits[(y + 1) * PIXEL_WIDTH + x] = 0;
+                self.ages[y * PIXEL_WIDTH + x] = 0;
+                self.ages[(y + 1) * PIXEL_WIDTH + x] = 0;
+                grow_dirty(&mut self.dirty, x, y);
+                grow_dirty(&mut self.dirty, x, y + 1);
+                return true;
+            }
+
+            let (left, right) = self.free_sides(x, y + 1);
+            let can_slide = left.is_some() || right.is_some();
+
+            // Viscous sand holds on for a while before sliding
+            let cell = y * PIXEL_WIDTH + x;
+            if !liquid && can_slide && self.waits[cell] < self.config.viscosity {
+                self.waits[cell] += 1;
+                self.busy = true;
+                return false;
+            }
+
+            // Sticky sand may refuse to slide. It doesn't keep the row awake,
+            // so it only tries again once something next to it moves.
+            if !liquid && can_slide && self.holds_on() {
+                return false;
+            }
+
+            // Try to move down-left, then down-right
+            if let Some(to_x) = self.pick_side(left, right) {
+                self.move_grain(x, y, to_x, y + 1);
+                return true;
+            }
+        }
+
+        // Wind blows a grain that can't fall along its row, a grain the gust
+        // missed keeps the row awake to be tried again
+        if let Some(to_x) = self.downwind(x, y) {
+            if self.gust() {
+                self.move_grain(x, y, to_x, y);
+                return true;
+            }
+            self.busy = true;
+        }
+
+        // Water that can't fall any further flows sideways to level out, but
+        // only toward a drop, so a level surface comes to rest
+        if liquid {
+            let (left, right) = self.free_sides(x, y);
+            let left = left.filter(|_| self.finds_drop(x, y, Self::left_of));
+            let right = right.filter(|_| self.finds_drop(x, y, Self::right_of));
+            if let Some(to_x) = self.pick_side(left, right) {
+                self.move_grain(x, y, to_x, y);
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+// Returns the row to resume from when the budget ran out mid-pass.
+// Rows that are not active are skipped, a processed row where nothing moved
+// goes inactive until a neighbouring row changes again.
+fn update_optimized(
+    step: &mut Step,
+    changed_rows: &mut [bool; ROWS],
+    active_rows: &mut [bool; ROWS],
+    skip_pattern: usize,
+    start_row: usize,
+) -> Option<usize> {
+    let region = step.config.region;
+    // The floor row is visited too, water on it can still flow sideways
+    let last_row = start_row.min(region.y1.saturating_sub(1));
+    let first_byte = region.x0 >> 3;
+    let last_byte = (region.x1.saturating_sub(1) >> 3).min(COLUMNS - 1);
+
+    for y in (region.y0..=last_row).rev() {
+        if step.budget == 0 {
+            return Some(y);
+        }
+
+        if y % skip_pattern != 0 || !active_rows[y] {
+            continue;
+        }
+
+        let row_start = y * COLUMNS;
+        let mut row_changed = false;
+        step.busy = false;
+
+        // Mirrored passes scan right to left, so grains competing for the
+        // same cell don't always resolve in the same order
+        for i in 0..=last_byte - first_byte {
+            let byte_idx = if step.mirror {
+                last_byte - i
+            } else {
+                first_byte + i
+            };
+            let mut byte_val = step.buffer[row_start + byte_idx];
+            if byte_idx == first_byte || byte_idx == last_byte {
+                byte_val &= region.column_mask(byte_idx);
+            }
+            if byte_val == 0 {
+                continue;
+            }
+
+            step.budget = step.budget.saturating_sub(byte_val.count_ones());
+            if step.update_byte(byte_val, byte_idx << 3, y) {
+                row_changed = true;
+            }
+        }
+
+        if row_changed {
+            changed_rows[y] = true;
+            active_rows[y] = true;
+            if y > 0 {
+                changed_rows[y - 1] = true;
+                active_rows[y - 1] = true;
+            }
+            if y < ROWS - 1 {
+                changed_rows[y + 1] = true;
+                active_rows[y + 1] = true;
+            }
+        } else if !step.busy {
+            active_rows[y] = false;
+        }
+    }
+
+    None
+}
+
+// Only the bytes that hold pixels, skipping the padding at the end of each row
+pub fn data_bytes(buffer: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    buffer
+        .chunks_exact(COLUMNS)
+        .flat_map(|row| row[..PIXEL_WIDTH / 8].iter().copied())
+}
+
+// Exact number of grains on the field, padding excluded
+#[must_use]
+pub fn count_sand(buffer: &[u8]) -> u32 {
+    data_bytes(buffer).map(u8::count_ones).sum()
+}
+
+// FNV-1a over the pixel bytes, padding excluded. Two fields with the same
+// grains hash the same, so a seeded run can be pinned to a golden value.
+#[must_use]
+pub fn field_checksum(buffer: &[u8]) -> u64 {
+    fnv1a(0xCBF2_9CE4_8422_2325, buffer)
+}
+
+fn fnv1a(hash: u64, buffer: &[u8]) -> u64 {
+    data_bytes(buffer).fold(hash, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+// Grains dropped by run_scenario
+const SCENARIO_GRAINS: usize = 6000;
+
+// Headless regression run: a seeded simulation with a scattered field and a
+// block of water, stepped `steps` times. Returns fnv_hash_state, so a physics
+// change shows up as a different value.
+#[must_use]
+pub fn run_scenario(seed: u64, steps: usize) -> u64 {
+    scenario(seed, steps).fnv_hash_state()
+}
+
+// The simulation run_scenario hashes
+fn scenario(seed: u64, steps: usize) -> Simulation {
+    let mut sim = Simulation::new(seed);
+    scatter(&mut *sim.buffer, SCENARIO_GRAINS, &mut rng::seeded(seed));
+    for y in 0..ROWS / 4 {
+        for x in PIXEL_WIDTH / 2..PIXEL_WIDTH / 2 + 40 {
+            set_pixel(&mut *sim.buffer, x, y, true);
+            set_pixel(&mut *sim.water, x, y, true);
+        }
+    }
+    sim.invalidate();
+    for _ in 0..steps {
+        sim.step();
+    }
+    sim
+}
+
+// Percent of the cells in x0..x1, y0..y1 that hold a grain, the rectangle is
+// clamped to the field and its edges need not be byte aligned
+#[must_use]
+pub fn density_in_rect(buffer: &[u8], x0: usize, y0: usize, x1: usize, y1: usize) -> u8 {
+    let rect = SimRegion {
+        x0,
+        y0,
+        x1: x1.min(PIXEL_WIDTH),
+        y1: y1.min(ROWS),
+    };
+    if rect.x0 >= rect.x1 || rect.y0 >= rect.y1 {
+        return 0;
+    }
+
+    let first_byte = rect.x0 >> 3;
+    let last_byte = (rect.x1 - 1) >> 3;
+    let first_mask = rect.column_mask(first_byte);
+    let last_mask = rect.column_mask(last_byte);
+
+    let mut pixel_count = 0u32;
+    for y in rect.y0..rect.y1 {
+        let row = &buffer[y * COLUMNS + first_byte..=y * COLUMNS + last_byte];
+        for (byte_idx, &byte) in (first_byte..).zip(row) {
+            let mut byte = byte;
+            if byte_idx == first_byte {
+                byte &= first_mask;
+            }
+            if byte_idx == last_byte {
+                byte &= last_mask;
+            }
+            pixel_count += byte.count_ones();
+        }
+    }
+    percent_of(pixel_count, (rect.x1 - rect.x0) * (rect.y1 - rect.y0))
+}
+
+// `part` out of `cells` as a percentage, `cells` is at most CELL_COUNT
+fn percent_of(part: u32, cells: usize) -> u8 {
+    let cells = u32::try_from(cells).unwrap();
+    u8::try_from((part * 100 / cells).min(100)).unwrap()
+}
+
+// Add a grain wherever a w x h, MSB-first 1-bit bitmap has a set bit, with
+// its top left corner at (dst_x, dst_y). Rows of `src` are `src_stride`
+// bytes apart. Whatever falls off the field, or past the end of `src`, is
+// skipped, and cells the bitmap leaves clear keep what they had.
+pub fn stamp_bitmap(
+    buffer: &mut [u8],
+    src: &[u8],
+    src_stride: usize,
+    dst_x: isize,
+    dst_y: isize,
+    w: usize,
+    h: usize,
+) {
+    let to_field = |offset: isize, i: usize| {
+        offset
+            .checked_add_unsigned(i)
+            .and_then(|v| usize::try_from(v).ok())
+    };
+    for sy in 0..h {
+        let Some(y) = to_field(dst_y, sy).filter(|&y| y < ROWS) else {
+            continue;
+        };
+        for sx in 0..w {
+            let Some(x) = to_field(dst_x, sx).filter(|&x| x < PIXEL_WIDTH) else {
+                continue;
+            };
+            let set = src
+                .get(sy * src_stride + (sx >> 3))
+                .is_some_and(|&byte| byte & BIT_MASKS[sx & 7] != 0);
+            if set {
+                set_pixel(buffer, x, y, true);
+            }
+        }
+    }
+}
+
+// Set or clear every cell in x0..x1, y0..y1, clamped to the field. Inner
+// bytes are written whole, only the edge bytes are masked.
+pub fn fill_rect(buffer: &mut [u8], x0: usize, y0: usize, x1: usize, y1: usize, value: bool) {
+    let rect = SimRegion {
+        x0,
+        y0,
+        x1: x1.min(PIXEL_WIDTH),
+        y1: y1.min(ROWS),
+    };
+    if rect.x0 >= rect.x1 || rect.y0 >= rect.y1 {
+        return;
+    }
+
+    let first_byte = rect.x0 >> 3;
+    let last_byte = (rect.x1 - 1) >> 3;
+    for y in rect.y0..rect.y1 {
+        let row = &mut buffer[y * COLUMNS + first_byte..=y * COLUMNS + last_byte];
+        for (byte_idx, byte) in (first_byte..).zip(row) {
+            let mask = if byte_idx == first_byte || byte_idx == last_byte {
+                rect.column_mask(byte_idx)
+            } else {
+                0xFF
+            };
+            if value {
+                *byte |= mask;
+            } else {
+                *byte &= !mask;
+            }
+        }
+    }
+}
+
+#[must_use]
+pub fn calculate_screen_density(buffer: &[u8]) -> u8 {
+    density_in_rect(buffer, 0, 0, PIXEL_WIDTH, ROWS)
+}
+
+// Topmost occupied row of every column, ROWS where the column is empty
+#[must_use]
+pub fn surface_heights(buffer: &[u8]) -> [u16; PIXEL_WIDTH] {
+    // ROWS in the type the heights are kept in
+    const EMPTY: u16 = 240;
+    const _: () = assert!(EMPTY as usize == ROWS);
+
+    let mut heights = [0; PIXEL_WIDTH];
+    for (x, height) in heights.iter_mut().enumerate() {
+        *height = (0..EMPTY)
+            .find(|&y| get_pixel(buffer, x, usize::from(y)))
+            .unwrap_or(EMPTY);
+    }
+    heights
+}
+
+// Every other row left out entirely
+fn hatch(y: usize) -> u8 {
+    if y & 1 == 0 {
+        0x00
+    } else {
+        0xFF
+    }
+}
+
+// Brick-like grid, a solid line every fourth row and posts in between
+fn mortar(y: usize) -> u8 {
+    if y % 4 == 0 {
+        0xFF
+    } else {
+        0x88
+    }
+}
+
+// A quarter of the pixels of a row, offset on alternate rows
+fn sparse_checker(y: usize) -> u8 {
+    if y & 1 == 0 {
+        0x88
+    } else {
+        0x22
+    }
+}
+
+// Half of the pixels of a row, offset on alternate rows
+pub fn checker(y: usize) -> u8 {
+    if y & 1 == 0 {
+        0xAA
+    } else {
+        0x55
+    }
+}
+
+// A fixed point that pours up to `rate` grains of sand a frame into the free
+// cells around it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Emitter {
+    pub x: usize,
+    pub y: usize,
+    pub rate: u8,
+}
+
+// Emitters spawn within this many pixels of their point on either axis
+pub const EMITTER_RADIUS: usize = 3;
+
+// The sand field and the state carried between frames of physics
+pub struct Simulation {
+    pub buffer: Box<[u8; BUFFER_SIZE]>, // Heap-allocated buffer to avoid stack overflow
+    // Set where the grain in `buffer` is water rather than sand, never set on
+    // an empty cell
+    pub water: Box<[u8; BUFFER_SIZE]>,
+    // Set where the grain is ash, lighter than sand but heavier than water.
+    // Never set on an empty cell or together with water.
+    pub ash: Box<[u8; BUFFER_SIZE]>,
+    // Set where the grain is stone, which the simulation never moves
+    pub stone: Box<[u8; BUFFER_SIZE]>,
+    // One blocked-update counter per cell, used by viscosity
+    pub waits: Box<[u8]>,
+    // Frames since each grain last moved, only kept while evaporation or
+    // shading is on
+    ages: Box<[u16]>,
+    pub physics: PhysicsConfig,
+    rng: GameRng,
+    active_rows: [bool; ROWS],
+    // Row an over-budget frame stopped at, the next frame carries on there
+    resume_row: Option<usize>,
+    // Scan direction of the next pass
+    mirror: bool,
+    // Grains that reach one of these are removed after each frame's passes
+    pub drains: Vec<SimRegion>,
+    pub emitters: Vec<Emitter>,
+    // Grains resting at least this many frames are drawn shaded
+    pub shade_age: Option<u16>,
+    // Cells the simulation changed since take_dirty was last called
+    dirty: Option<DirtyRegion>,
+}
+
+impl Simulation {
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        Self {
+            buffer: Box::new([0; BUFFER_SIZE]),
+            water: Box::new([0; BUFFER_SIZE]),
+            ash: Box::new([0; BUFFER_SIZE]),
+            stone: Box::new([0; BUFFER_SIZE]),
+            waits: vec![0; CELL_COUNT].into_boxed_slice(), // Too big to build on the stack
+            ages: vec![0; CELL_COUNT].into_boxed_slice(),
+            physics: PhysicsConfig::default(),
+            rng: rng::seeded(seed),
+            active_rows: [false; ROWS],
+            resume_row: None,
+            mirror: false,
+            drains: Vec::new(),
+            emitters: Vec::new(),
+            shade_age: None,
+            dirty: None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        clear_buffer(&mut *self.buffer);
+        clear_buffer(&mut *self.water);
+        clear_buffer(&mut *self.ash);
+        clear_buffer(&mut *self.stone);
+        self.invalidate();
+    }
+
+    // Call after the buffer was rewritten from outside: wait counters and an
+    // interrupted pass no longer match it, and every row may move again
+    pub fn invalidate(&mut self) {
+        self.waits.fill(0);
+        self.ages.fill(0);
+        self.resume_row = None;
+        self.active_rows = [true; ROWS];
+        self.dirty = Some(DirtyRegion::FULL);
+    }
+
+    // Run up to `passes` passes over every `skip_pattern`th row. Rows marked
+    // in `changed_rows` beforehand are woken up, rows that moved are marked
+    // on the way out. Returns how many grains landed.
+    pub fn advance(
+        &mut self,
+        passes: u8,
+        skip_pattern: usize,
+        changed_rows: &mut [bool; ROWS],
+    ) -> u32 {
+        // Brush strokes and full redraws wake the rows they touched
+        for (active, &changed) in self.active_rows.iter_mut().zip(changed_rows.iter()) {
+            *active |= changed;
+        }
+
+        let mut step = Step {
+            buffer: &mut *self.buffer,
+            water: &mut *self.water,
+            ash: &mut *self.ash,
+            stone: &*self.stone,
+            waits: &mut self.waits,
+            ages: &mut self.ages,
+            config: self.physics,
+            rng: &mut self.rng,
+            budget: UPDATE_BUDGET,
+            busy: false,
+            landings: 0,
+            mirror: self.mirror,
+            dirty: self.dirty,
+        };
+        for _ in 0..passes {
+            // Pick up where an over-budget frame stopped before starting a new pass
+            let start_row = self.resume_row.take().unwrap_or(ROWS - 1);
+            self.resume_row = update_optimized(
+                &mut step,
+                changed_rows,
+                &mut self.active_rows,
+                skip_pattern,
+                start_row,
+            );
+            if self.resume_row.is_some() {
+                break;
+            }
+            step.mirror = !step.mirror;
+        }
+
+        self.mirror = step.mirror;
+        self.dirty = step.dirty;
+        let landings = step.landings;
+        self.sweep_drains(changed_rows);
+        self.run_emitters(changed_rows);
+        if self.physics.evaporation_ticks.is_some() || self.shade_age.is_some() {
+            self.age_grains(changed_rows);
+        }
+        landings
+    }
+
+    // Age every grain in the region by one frame. With evaporation on, grains
+    // that rested longer than its limit are removed. Rows are marked when a
+    // grain reaches shade_age so it gets redrawn. Empty cells go back to
+    // age 0, so poured and spawned grains always start fresh.
+    fn age_grains(&mut self, changed_rows: &mut [bool; ROWS]) {
+        let region = self.physics.region;
+        let max_age = self.physics.evaporation_ticks;
+        for y in region.y0..region.y1 {
+            let mut removed = false;
+            for x in region.x0..region.x1 {
+                let cell = y * PIXEL_WIDTH + x;
+                // Stone is scenery, it neither evaporates nor wears
+                if !get_pixel(&*self.buffer, x, y) || get_pixel(&*self.stone, x, y) {
+                    self.ages[cell] = 0;
+                } else if max_age.is_some_and(|max_age| self.ages[cell] >= max_age) {
+                    self.set_cell(x, y, None);
+                    grow_dirty(&mut self.dirty, x, y);
+                    removed = true;
+                } else {
+                    self.ages[cell] = self.ages[cell].saturating_add(1);
+                    if Some(self.ages[cell]) == self.shade_age {
+                        changed_rows[y] = true;
+                        grow_dirty(&mut self.dirty, x, y);
+                    }
+                }
+            }
+            // Passes have already run, wake the grains above for the next one
+            if removed {
+                self.wake_row(y, changed_rows);
+            }
+        }
+    }
+
+    // Mark row `y` and the one above it changed and active again, after
+    // grains were removed outside of a pass
+    fn wake_row(&mut self, y: usize, changed_rows: &mut [bool; ROWS]) {
+        for y in [y, y.saturating_sub(1)] {
+            changed_rows[y] = true;
+            self.active_rows[y] = true;
+        }
+    }
+
+    // Bits of buffer byte `index` holding grains old enough to be drawn
+    // shaded, always 0 while shading is off
+    #[must_use]
+    pub fn shaded_bits(&self, index: usize) -> u8 {
+        let Some(shade_age) = self.shade_age else {
+            return 0;
+        };
+        let (y, first_x) = (index / COLUMNS, (index % COLUMNS) << 3);
+        let byte = self.buffer[index];
+        if byte == 0 || first_x >= PIXEL_WIDTH {
+            return 0;
+        }
+
+        let mut bits = 0;
+        for (bit, &mask) in BIT_MASKS.iter().enumerate() {
+            if byte & mask != 0 && self.ages[y * PIXEL_WIDTH + first_x + bit] >= shade_age {
+                bits |= mask;
+            }
+        }
+        bits
+    }
+
+    // Write row `y` as it is drawn into `out`, only touching the bytes that
+    // differ. Water is dithered so it stands apart from sand, ash is
+    // hatched, stone is bricked and old sand gets a lighter dither.
+    pub fn render_row(&self, y: usize, out: &mut [u8]) {
+        let hidden = !checker(y);
+        let worn = sparse_checker(y);
+        let hatched = hatch(y);
+        let bricked = !mortar(y);
+        let row_start = y * COLUMNS;
+        for (i, out) in (row_start..row_start + COLUMNS).zip(out) {
+            let (water, ash, stone) = (self.water[i], self.ash[i], self.stone[i]);
+            let shaded = self.shaded_bits(i) & !water & !ash & !stone;
+            let byte_val = self.buffer[i]
+                & !(water & hidden)
+                & !(ash & hatched)
+                & !(stone & bricked)
+                & !(shaded & worn);
+            if *out != byte_val {
+                *out = byte_val;
+            }
+        }
+    }
+
+    // Write the whole field as it is drawn into any packed 1-bit buffer with
+    // the COLUMNS stride, so a host-side tool can render it without the
+    // device frame
+    pub fn render_frame(&self, out: &mut [u8]) {
+        for (y, row) in out.chunks_mut(COLUMNS).take(ROWS).enumerate() {
+            self.render_row(y, row);
+        }
+    }
+
+    // Clear every grain inside a drain. Only the part of a drain within the
+    // region is swept, sand outside it stays untouched.
+    fn sweep_drains(&mut self, changed_rows: &mut [bool; ROWS]) {
+        let region = self.physics.region;
+        for drain in self.drains.clone() {
+            let (x0, x1) = (drain.x0.max(region.x0), drain.x1.min(region.x1));
+            for y in drain.y0.max(region.y0)..drain.y1.min(region.y1) {
+                let mut removed = false;
+                for x in x0..x1 {
+                    if get_pixel(&*self.buffer, x, y) {
+                        self.set_cell(x, y, None);
+                        grow_dirty(&mut self.dirty, x, y);
+                        removed = true;
+                    }
+                }
+                // The row above may be able to fall into the space now
+                if removed {
+                    self.wake_row(y, changed_rows);
+                }
+            }
+        }
+    }
+
+    // Put a grain of `material` at (x, y), or empty the cell for None. The
+    // caller marks the row changed.
+    pub fn set_cell(&mut self, x: usize, y: usize, material: Option<Material>) {
+        set_pixel(&mut *self.buffer, x, y, material.is_some());
+        set_pixel(&mut *self.water, x, y, material == Some(Material::Water));
+        set_pixel(&mut *self.ash, x, y, material == Some(Material::Ash));
+        set_pixel(&mut *self.stone, x, y, material == Some(Material::Stone));
+        self.waits[y * PIXEL_WIDTH + x] = 0;
+        self.ages[y * PIXEL_WIDTH + x] = 0;
+    }
+
+    // Material of the grain at (x, y), None for an empty cell
+    fn cell(&self, x: usize, y: usize) -> Option<Material> {
+        if !get_pixel(&*self.buffer, x, y) {
+            None
+        } else if get_pixel(&*self.water, x, y) {
+            Some(Material::Water)
+        } else if get_pixel(&*self.ash, x, y) {
+            Some(Material::Ash)
+        } else if get_pixel(&*self.stone, x, y) {
+            Some(Material::Stone)
+        } else {
+            Some(Material::Sand)
+        }
+    }
+
+    // Turn every resting sand or ash grain in the region into stone, grains
+    // that could still fall or slide and water are left as they are. A grain
+    // rests on the floor, on stone or on a resting grain at least as heavy,
+    // with both cells diagonally below it taken. Returns whether anything
+    // froze.
+    pub fn freeze(&mut self) -> bool {
+        let region = self.physics.region;
+        // Whether the grain in each column of the row below rests, worked
+        // out from the floor up
+        let mut resting = [false; PIXEL_WIDTH];
+        let mut frozen = false;
+        for y in (region.y0..region.y1).rev() {
+            let below = resting;
+            let floor = y + 1 == region.y1;
+            for x in region.x0..region.x1 {
+                let Some(material) = self.cell(x, y) else {
+                    resting[x] = false;
+                    continue;
+                };
+                let held = floor
+                    || below[x]
+                        && self
+                            .cell(x, y + 1)
+                            .is_some_and(|under| under.density() >= material.density());
+                let blocked = floor
+                    || [self.physics.left_of(x), self.physics.right_of(x)]
+                        .into_iter()
+                        .flatten()
+                        .all(|side| get_pixel(&*self.buffer, side, y + 1));
+                resting[x] = material == Material::Stone || held && blocked;
+                if resting[x] && matches!(material, Material::Sand | Material::Ash) {
+                    self.set_cell(x, y, Some(Material::Stone));
+                    frozen = true;
+                }
+            }
+        }
+        if frozen {
+            self.dirty = Some(DirtyRegion::FULL);
+        }
+        frozen
+    }
+
+    // field_checksum carried on over the material planes, so two simulations
+    // hash the same only if every grain and its material match
+    #[must_use]
+    pub fn fnv_hash_state(&self) -> u64 {
+        [&*self.water, &*self.ash, &*self.stone]
+            .into_iter()
+            .fold(field_checksum(&*self.buffer), |hash, plane| {
+                fnv1a(hash, plane)
+            })
+    }
+
+    // Each emitter tries `rate` random cells around its point and fills the
+    // free ones, so a buried emitter stops pouring until there is room again
+    fn run_emitters(&mut self, changed_rows: &mut [bool; ROWS]) {
+        let region = self.physics.region;
+        let span = u32::try_from(2 * EMITTER_RADIUS + 1).unwrap();
+        for emitter in self.emitters.clone() {
+            for _ in 0..emitter.rate {
+                let dx = rand_range(&mut self.rng, 0, span) as usize;
+                let dy = rand_range(&mut self.rng, 0, span) as usize;
+                let (Some(x), Some(y)) = (
+                    (emitter.x + dx).checked_sub(EMITTER_RADIUS),
+                    (emitter.y + dy).checked_sub(EMITTER_RADIUS),
+                ) else {
+                    continue;
+                };
+                if region.contains(x, y) && !get_pixel(&*self.buffer, x, y) {
+                    self.set_cell(x, y, Some(Material::Sand));
+                    grow_dirty(&mut self.dirty, x, y);
+                    changed_rows[y] = true;
+                    // Passes have already run, wake the row for the next one
+                    self.active_rows[y] = true;
+                }
+            }
+        }
+    }
+
+    // Restart the simulation rng from `seed`, the field is kept as it is
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = rng::seeded(seed);
+    }
+
+    // True `percent` times in 100, drawn from the simulation rng. 100 and up
+    // always hold without touching the rng.
+    pub fn chance(&mut self, percent: u8) -> bool {
+        percent >= 100 || self.rng.next_u32() % 100 < u32::from(percent)
+    }
+
+    // Bounding box of everything changed since the last call, None when the
+    // field is unchanged. A clear or invalidate counts as the whole field.
+    pub fn take_dirty(&mut self) -> Option<DirtyRegion> {
+        self.dirty.take()
+    }
+
+    // A single full-quality pass, returns the rows that changed
+    pub fn step(&mut self) -> [bool; ROWS] {
+        let mut changed_rows = [false; ROWS];
+        self.advance(1, 1, &mut changed_rows);
+        changed_rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // run_scenario(42, 500) and the field_checksum of the same run, for each
+    // generator. rand keeps SmallRng's stream stable within 0.8.x, these are
+    // the values of its 64-bit version that host tests run with.
+    #[cfg(not(feature = "xorshift"))]
+    const GOLDEN_SCENARIO: (u64, u64) = (0x22d4_5a9c_5048_920f, 0xb6f6_dcba_87c5_d147);
+    #[cfg(feature = "xorshift")]
+    const GOLDEN_SCENARIO: (u64, u64) = (0xdea6_307f_734d_77ea, 0x71e5_0da7_0336_4698);
+
+    #[test]
+    fn scenario_matches_golden() {
+        let sim = scenario(42, 500);
+        let golden = (sim.fnv_hash_state(), field_checksum(&*sim.buffer));
+        assert_eq!(golden, GOLDEN_SCENARIO);
+        assert_eq!(run_scenario(42, 500), GOLDEN_SCENARIO.0);
+    }
+
+    #[test]
+    fn scenario_is_deterministic() {
+        assert_eq!(run_scenario(42, 200), run_scenario(42, 200));
+        assert_ne!(run_scenario(42, 200), run_scenario(43, 200));
+    }
+
+    // A seeded field stepped entirely on the host, no pd involved
+    #[test]
+    fn thousand_steps_settle_without_losing_grains() {
+        let mut sim = Simulation::new(7);
+        scatter(&mut *sim.buffer, 3000, &mut rng::seeded(7));
+        sim.invalidate();
+        let grains = count_sand(&*sim.buffer);
+
+        for _ in 0..1000 {
+            sim.step();
+        }
+
+        assert_eq!(count_sand(&*sim.buffer), grains);
+        assert_eq!(sim.step(), [false; ROWS]);
+        // Everything ends up resting on the floor or on another grain
+        for y in 0..ROWS - 1 {
+            for x in 0..PIXEL_WIDTH {
+                if get_pixel(&*sim.buffer, x, y) {
+                    assert!(get_pixel(&*sim.buffer, x, y + 1), "({x}, {y}) floats");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reseeding_repeats_a_fresh_run() {
+        let mut fresh = Simulation::new(5);
+        let mut reseeded = Simulation::new(6);
+        reseeded.reseed(5);
+        for sim in [&mut fresh, &mut reseeded] {
+            scatter(&mut *sim.buffer, 2000, &mut rng::seeded(1));
+            sim.invalidate();
+            for _ in 0..100 {
+                sim.step();
+            }
+        }
+        assert_eq!(fresh.fnv_hash_state(), reseeded.fnv_hash_state());
+    }
+
+    #[test]
+    fn row_padding_is_not_counted() {
+        let mut buffer = [0u8; BUFFER_SIZE];
+        for row in buffer.chunks_exact_mut(COLUMNS) {
+            row[PIXEL_WIDTH / 8..].fill(0xFF);
+        }
+        assert_eq!(count_sand(&buffer), 0);
+        assert_eq!(calculate_screen_density(&buffer), 0);
+        assert_eq!(data_bytes(&buffer).count(), PIXEL_WIDTH / 8 * ROWS);
+        assert_eq!(field_checksum(&buffer), field_checksum(&[0; BUFFER_SIZE]));
+    }
+
+    #[test]
+    fn checksum_tells_fields_apart() {
+        let mut sim = scenario(42, 100);
+        let before = field_checksum(&*sim.buffer);
+        assert_eq!(field_checksum(&*scenario(42, 100).buffer), before);
+
+        let grain = get_pixel(&*sim.buffer, 0, 0);
+        set_pixel(&mut *sim.buffer, 0, 0, !grain);
+        assert_ne!(field_checksum(&*sim.buffer), before);
+    }
+
+    #[test]
+    fn grains_on_a_peak_slide_both_ways_evenly() {
+        let mut sim = Simulation::new(11);
+        let (x, y) = (PIXEL_WIDTH / 2, ROWS - 2);
+        let mut left = 0;
+        for _ in 0..2000 {
+            sim.clear();
+            set_pixel(&mut *sim.buffer, x, y + 1, true);
+            set_pixel(&mut *sim.buffer, x, y, true);
+            sim.step();
+            if get_pixel(&*sim.buffer, x - 1, y + 1) {
+                left += 1;
+            } else {
+                assert!(get_pixel(&*sim.buffer, x + 1, y + 1));
+            }
+        }
+        assert!((900..=1100).contains(&left), "{left} of 2000 went left");
+    }
+
+    // A column of sand dropped on an empty floor, stepped until it settles.
+    // Returns how tall the pile ends up.
+    fn pile_height(cohesion: u8) -> usize {
+        let mut sim = Simulation::new(13);
+        sim.physics.cohesion = cohesion;
+        fill_rect(
+            &mut *sim.buffer,
+            PIXEL_WIDTH / 2,
+            0,
+            PIXEL_WIDTH / 2 + 4,
+            ROWS,
+            true,
+        );
+        sim.invalidate();
+        for _ in 0..2000 {
+            sim.step();
+        }
+        let top = surface_heights(&*sim.buffer).into_iter().min().unwrap();
+        ROWS - usize::from(top)
+    }
+
+    #[test]
+    fn cohesion_keeps_piles_steeper() {
+        let loose = pile_height(0);
+        let sticky = pile_height(80);
+        assert!(
+            sticky > loose + 10,
+            "cohesion 80: {sticky}, cohesion 0: {loose}"
+        );
+    }
+
+    #[test]
+    fn density_in_rect_counts_unaligned_edges() {
+        let mut buffer = [0u8; BUFFER_SIZE];
+        assert_eq!(density_in_rect(&buffer, 0, 0, PIXEL_WIDTH, ROWS), 0);
+
+        fill_rect(&mut buffer, 3, 5, 21, 15, true);
+        assert_eq!(density_in_rect(&buffer, 3, 5, 21, 15), 100);
+        // Half of the 36 columns, the edges falling mid-byte
+        assert_eq!(density_in_rect(&buffer, 12, 5, 30, 15), 50);
+        assert_eq!(density_in_rect(&buffer, 21, 5, 40, 15), 0);
+        // Rects past the field are clamped, empty ones count as 0
+        assert_eq!(density_in_rect(&buffer, 3, 5, 3, 15), 0);
+        fill_rect(&mut buffer, 0, 0, PIXEL_WIDTH, ROWS, true);
+        assert_eq!(density_in_rect(&buffer, 390, 230, 1000, 1000), 100);
+    }
+
+    #[test]
+    fn drains_empty_out_and_stay_empty() {
+        let mut sim = Simulation::new(17);
+        let drain = SimRegion {
+            x0: 150,
+            y0: ROWS - 10,
+            x1: 250,
+            y1: ROWS,
+        };
+        sim.drains.push(drain);
+        fill_rect(&mut *sim.buffer, 180, 0, 220, 40, true);
+        sim.invalidate();
+
+        for _ in 0..600 {
+            sim.step();
+        }
+        assert_eq!(count_sand(&*sim.buffer), 0);
+        sim.step();
+        assert_eq!(count_sand(&*sim.buffer), 0);
+    }
+
+    #[test]
+    fn count_sand_counts_every_set_bit() {
+        let mut buffer = [0u8; BUFFER_SIZE];
+        assert_eq!(count_sand(&buffer), 0);
+        // A partly filled byte, the last column and the last cell
+        for (x, y) in [
+            (0, 0),
+            (3, 0),
+            (7, 0),
+            (PIXEL_WIDTH - 1, 0),
+            (PIXEL_WIDTH - 1, ROWS - 1),
+        ] {
+            set_pixel(&mut buffer, x, y, true);
+        }
+        assert_eq!(count_sand(&buffer), 5);
+        fill_rect(&mut buffer, 0, 0, PIXEL_WIDTH, ROWS, true);
+        assert_eq!(count_sand(&buffer), CELL_COUNT as u32);
+    }
+
+    #[test]
+    fn grains_fall_into_cells_emptied_by_evaporation() {
+        let mut sim = Simulation::new(19);
+        sim.physics.evaporation_ticks = Some(40);
+        let (x, floor) = (PIXEL_WIDTH / 2, ROWS - 1);
+        fill_rect(&mut *sim.buffer, 0, floor, PIXEL_WIDTH, ROWS, true);
+        sim.invalidate();
+        for _ in 0..30 {
+            sim.step();
+        }
+
+        // A fresh grain lands on the old floor and its row goes back to sleep
+        sim.set_cell(x, floor - 1, Some(Material::Sand));
+        let mut changed_rows = [false; ROWS];
+        changed_rows[floor - 1] = true;
+        sim.advance(1, 1, &mut changed_rows);
+        for _ in 0..15 {
+            sim.step();
+        }
+
+        // The old grain evaporated underneath it and the fresh one dropped in
+        assert!(get_pixel(&*sim.buffer, x, floor));
+        assert!(!get_pixel(&*sim.buffer, x, floor - 1));
+    }
+
+    #[test]
+    fn rendered_frame_draws_materials_apart() {
+        let mut sim = Simulation::new(1);
+        fill_rect(&mut *sim.buffer, 0, 0, 32, 1, true);
+        for x in 8..16 {
+            sim.set_cell(x, 0, Some(Material::Water));
+        }
+        for x in 24..32 {
+            sim.set_cell(x, 0, Some(Material::Stone));
+        }
+        let mut frame = [0u8; BUFFER_SIZE];
+        sim.render_frame(&mut frame);
+
+        // Sand stays solid and so does stone on a mortar row, water is dithered
+        assert_eq!(frame[..4], [0xFF, checker(0), 0xFF, 0xFF]);
+        assert!(frame[4..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn fill_rect_matches_a_pixel_loop() {
+        for (x0, y0, x1, y1) in [
+            (0, 0, 8, 1),
+            (3, 2, 5, 9),
+            (5, 10, 203, 12),
+            (390, 230, 500, 300),
+        ] {
+            for value in [true, false] {
+                let mut filled = [0x5Au8; BUFFER_SIZE];
+                let mut naive = filled;
+                fill_rect(&mut filled, x0, y0, x1, y1, value);
+                for y in y0..y1.min(ROWS) {
+                    for x in x0..x1.min(PIXEL_WIDTH) {
+                        set_pixel(&mut naive, x, y, value);
+                    }
+                }
+                assert!(filled == naive, "({x0}, {y0}, {x1}, {y1}) {value}");
+            }
+        }
+    }
+
+    #[test]
+    fn dirty_region_covers_what_moved() {
+        let mut sim = Simulation::new(23);
+        assert_eq!(sim.take_dirty(), None);
+        sim.clear();
+        assert_eq!(sim.take_dirty(), Some(DirtyRegion::FULL));
+
+        // A grain on the floor that can't go anywhere
+        sim.set_cell(10, ROWS - 1, Some(Material::Sand));
+        sim.step();
+        assert_eq!(sim.take_dirty(), None);
+
+        // A falling grain moves one row down, a 1x2 box
+        sim.set_cell(100, 50, Some(Material::Sand));
+        let mut changed_rows = [false; ROWS];
+        changed_rows[50] = true;
+        sim.advance(1, 1, &mut changed_rows);
+        let moved = DirtyRegion {
+            min_x: 100,
+            max_x: 100,
+            min_y: 50,
+            max_y: 51,
+        };
+        assert_eq!(sim.take_dirty(), Some(moved));
+        assert_eq!(sim.take_dirty(), None);
+
+        // A single drained grain is a 1x1 box
+        sim.set_cell(100, 51, None);
+        sim.drains.push(SimRegion {
+            x0: 10,
+            y0: ROWS - 1,
+            x1: 11,
+            y1: ROWS,
+        });
+        sim.step();
+        let drained = DirtyRegion {
+            min_x: 10,
+            max_x: 10,
+            min_y: ROWS - 1,
+            max_y: ROWS - 1,
+        };
+        assert_eq!(sim.take_dirty(), Some(drained));
+
+        sim.invalidate();
+        assert_eq!(sim.take_dirty(), Some(DirtyRegion::FULL));
+    }
+
+    #[test]
+    fn heavier_grains_sink_through_lighter_ones() {
+        let mut sim = Simulation::new(25);
+        let (x0, x1) = (100, 140);
+        // Sand over ash over water, wall to wall of the region
+        sim.physics.region = SimRegion {
+            x0,
+            y0: 0,
+            x1,
+            y1: ROWS,
+        };
+        for (material, y0) in [
+            (Material::Sand, 200),
+            (Material::Ash, 210),
+            (Material::Water, 220),
+        ] {
+            for y in y0..y0 + 10 {
+                for x in x0..x1 {
+                    sim.set_cell(x, y, Some(material));
+                }
+            }
+        }
+        sim.invalidate();
+        for _ in 0..500 {
+            sim.step();
+        }
+
+        assert_eq!(count_sand(&*sim.buffer), 1200);
+        assert_eq!(count_sand(&*sim.water), 400);
+        assert_eq!(count_sand(&*sim.ash), 400);
+        // Sand ends up at the bottom and water on top
+        for x in x0..x1 {
+            assert!(!get_pixel(&*sim.water, x, ROWS - 1) && !get_pixel(&*sim.ash, x, ROWS - 1));
+            assert!(get_pixel(&*sim.water, x, 210));
+        }
+    }
+
+    #[test]
+    fn resting_grains_freeze_into_stone() {
+        let mut sim = Simulation::new(27);
+        let x = PIXEL_WIDTH / 2;
+        for x in x - 1..=x + 1 {
+            sim.set_cell(x, ROWS - 1, Some(Material::Sand));
+        }
+        sim.set_cell(x, ROWS - 2, Some(Material::Sand));
+        sim.set_cell(x + 5, ROWS - 1, Some(Material::Water));
+        sim.set_cell(x + 9, 10, Some(Material::Sand));
+        assert!(sim.freeze());
+
+        // Resting sand turned to stone, falling sand and water did not
+        assert!(get_pixel(&*sim.stone, x, ROWS - 1) && get_pixel(&*sim.stone, x, ROWS - 2));
+        assert!(!get_pixel(&*sim.stone, x + 5, ROWS - 1));
+        assert!(!get_pixel(&*sim.stone, x + 9, 10));
+
+        // The stone stays put while a grain dropped on it slides down its
+        // side to the floor
+        sim.set_cell(x, ROWS - 3, Some(Material::Sand));
+        sim.invalidate();
+        for _ in 0..50 {
+            sim.step();
+        }
+        assert!(get_pixel(&*sim.stone, x, ROWS - 1) && get_pixel(&*sim.stone, x, ROWS - 2));
+        assert!(!get_pixel(&*sim.buffer, x, ROWS - 3));
+        assert!(
+            get_pixel(&*sim.buffer, x - 2, ROWS - 1) || get_pixel(&*sim.buffer, x + 2, ROWS - 1)
+        );
+    }
+
+    #[test]
+    fn grains_that_could_still_move_do_not_freeze() {
+        let mut sim = Simulation::new(37);
+        let x = PIXEL_WIDTH / 2;
+        // A stack falling in mid-air
+        for y in 100..103 {
+            sim.set_cell(x, y, Some(Material::Sand));
+        }
+        // Sand floating on a pool of water
+        for x in x + 10..x + 20 {
+            sim.set_cell(x, ROWS - 1, Some(Material::Water));
+        }
+        sim.set_cell(x + 15, ROWS - 2, Some(Material::Sand));
+        // A grain on another with free cells beside it
+        sim.set_cell(x - 10, ROWS - 1, Some(Material::Sand));
+        sim.set_cell(x - 10, ROWS - 2, Some(Material::Sand));
+        assert!(sim.freeze());
+
+        assert_eq!(count_sand(&*sim.stone), 1);
+        assert!(get_pixel(&*sim.stone, x - 10, ROWS - 1));
+    }
+
+    #[test]
+    fn surface_heights_find_the_top_grain() {
+        let mut buffer = [0u8; BUFFER_SIZE];
+        assert!(surface_heights(&buffer)
+            .iter()
+            .all(|&h| usize::from(h) == ROWS));
+
+        set_pixel(&mut buffer, 5, ROWS - 1, true);
+        fill_rect(&mut buffer, 7, 0, 8, ROWS, true);
+        set_pixel(&mut buffer, 9, 100, true);
+        set_pixel(&mut buffer, 9, 150, true);
+        let heights = surface_heights(&buffer);
+        assert_eq!(heights[5..10], [239, 240, 0, 240, 100]);
+    }
+
+    #[test]
+    fn emitters_pour_at_their_rate_until_removed() {
+        let mut sim = Simulation::new(29);
+        sim.emitters.push(Emitter {
+            x: PIXEL_WIDTH / 2,
+            y: 20,
+            rate: 4,
+        });
+        sim.invalidate();
+        sim.step();
+        assert!((1..=4).contains(&count_sand(&*sim.buffer)));
+
+        for _ in 0..99 {
+            sim.step();
+        }
+        // Most tries land on a free cell while the grains keep falling away
+        let poured = count_sand(&*sim.buffer);
+        assert!((300..=400).contains(&poured), "{poured} grains poured");
+
+        sim.emitters.clear();
+        for _ in 0..100 {
+            sim.step();
+        }
+        assert_eq!(count_sand(&*sim.buffer), poured);
+    }
+
+    #[test]
+    fn stamped_bitmaps_clip_at_the_field_edges() {
+        // X.X
+        // .X.
+        let src = [0b1010_0000, 0b0100_0000];
+        let cells = |buffer: &[u8]| {
+            let mut cells = alloc::vec::Vec::new();
+            for y in 0..ROWS {
+                for x in 0..PIXEL_WIDTH {
+                    if get_pixel(buffer, x, y) {
+                        cells.push((x, y));
+                    }
+                }
+            }
+            cells
+        };
+
+        let mut buffer = [0u8; BUFFER_SIZE];
+        stamp_bitmap(&mut buffer, &src, 1, 10, 20, 3, 2);
+        assert_eq!(cells(&buffer), [(10, 20), (12, 20), (11, 21)]);
+
+        // Hanging off the top left and the bottom right corners
+        buffer = [0; BUFFER_SIZE];
+        stamp_bitmap(&mut buffer, &src, 1, -1, -1, 3, 2);
+        assert_eq!(cells(&buffer), [(0, 0)]);
+        let (right, bottom) = (
+            isize::try_from(PIXEL_WIDTH).unwrap(),
+            isize::try_from(ROWS).unwrap(),
+        );
+        buffer = [0; BUFFER_SIZE];
+        stamp_bitmap(&mut buffer, &src, 1, right - 2, bottom - 1, 3, 2);
+        assert_eq!(cells(&buffer), [(PIXEL_WIDTH - 2, ROWS - 1)]);
+
+        // Fully off the field, or rows past the end of the bitmap
+        buffer = [0; BUFFER_SIZE];
+        stamp_bitmap(&mut buffer, &src, 1, -5, 0, 3, 2);
+        stamp_bitmap(&mut buffer, &src, 1, 0, bottom, 3, 2);
+        assert!(cells(&buffer).is_empty());
+        stamp_bitmap(&mut buffer, &src, 1, 0, 0, 3, 4);
+        assert_eq!(cells(&buffer).len(), 3);
+    }
+
+    // Sum of the columns of every grain on the field and the grain count,
+    // the centroid is their ratio
+    fn column_sum(buffer: &[u8]) -> (usize, usize) {
+        let (mut sum, mut count) = (0, 0);
+        for y in 0..ROWS {
+            for x in 0..PIXEL_WIDTH {
+                if get_pixel(buffer, x, y) {
+                    sum += x;
+                    count += 1;
+                }
+            }
+        }
+        (sum, count)
+    }
+
+    #[test]
+    fn mirrored_passes_keep_a_pile_centred() {
+        let mut sim = Simulation::new(31);
+        // Without the coin flip only the alternating scan keeps it even
+        sim.physics.random_diagonal = false;
+        let x = PIXEL_WIDTH / 2;
+        fill_rect(&mut *sim.buffer, x, ROWS - 120, x + 1, ROWS, true);
+        sim.invalidate();
+        for step in 0..200 {
+            sim.step();
+            // Within 2 px of the spawn column
+            let (sum, count) = column_sum(&*sim.buffer);
+            assert!(sum.abs_diff(x * count) <= 2 * count, "step {step}");
+        }
+    }
+
+    #[test]
+    fn water_levels_out_and_comes_to_rest() {
+        let mut sim = Simulation::new(33);
+        let x = PIXEL_WIDTH / 2;
+        for y in ROWS - 60..ROWS {
+            sim.set_cell(x, y, Some(Material::Water));
+        }
+        sim.set_cell(50, ROWS - 1, Some(Material::Water));
+        sim.invalidate();
+        for _ in 0..1000 {
+            sim.step();
+        }
+
+        // A lone drop on the floor stays where it is
+        assert!(get_pixel(&*sim.water, 50, ROWS - 1));
+        // The column spread into a puddle that no longer moves
+        let heights = surface_heights(&*sim.buffer);
+        assert!(heights.iter().all(|&h| usize::from(h) >= ROWS - 2));
+        assert_eq!(sim.step(), [false; ROWS]);
+    }
+    // A grain with only the far side of the edge free to slide to
+    fn slide_across_edge(edge: usize, inner: usize) -> Simulation {
+        let mut sim = Simulation::new(26);
+        sim.physics.wrap_x = true;
+        let floor = ROWS - 1;
+        sim.set_cell(edge, floor, Some(Material::Stone));
+        sim.set_cell(inner, floor, Some(Material::Stone));
+        sim.set_cell(edge, floor - 1, Some(Material::Sand));
+        sim.invalidate();
+        for _ in 0..20 {
+            sim.step();
+        }
+        sim
+    }
+
+    #[test]
+    fn grains_leaving_the_right_edge_wrap_to_the_left() {
+        let sim = slide_across_edge(PIXEL_WIDTH - 1, PIXEL_WIDTH - 2);
+        assert!(get_pixel(&*sim.buffer, 0, ROWS - 1));
+        assert!(!get_pixel(&*sim.buffer, PIXEL_WIDTH - 1, ROWS - 2));
+        assert_eq!(count_sand(&*sim.buffer), 3);
+    }
+
+    #[test]
+    fn grains_leaving_the_left_edge_wrap_to_the_right() {
+        let sim = slide_across_edge(0, 1);
+        assert!(get_pixel(&*sim.buffer, PIXEL_WIDTH - 1, ROWS - 1));
+        assert!(!get_pixel(&*sim.buffer, 0, ROWS - 2));
+        assert_eq!(count_sand(&*sim.buffer), 3);
+    }
+
+    #[test]
+    fn wind_blows_a_pile_over_to_the_wall() {
+        let mut sim = Simulation::new(35);
+        let (x0, x1) = (100, 160);
+        sim.physics.region = SimRegion {
+            x0,
+            y0: 0,
+            x1,
+            y1: ROWS,
+        };
+        sim.physics.wind = MAX_WIND / 2;
+        fill_rect(&mut *sim.buffer, 110, ROWS - 20, 120, ROWS, true);
+        sim.invalidate();
+        let (start, count) = column_sum(&*sim.buffer);
+        for _ in 0..100 {
+            sim.step();
+        }
+        let (sum, _) = column_sum(&*sim.buffer);
+        assert!(sum > start);
+
+        for _ in 0..1000 {
+            sim.step();
+        }
+        let (sum, left) = column_sum(&*sim.buffer);
+        assert_eq!(left, count);
+        assert!(sum > start);
+        // Packed against the right wall, no grain has room downwind
+        for y in 0..ROWS {
+            for x in x0..x1 - 1 {
+                if get_pixel(&*sim.buffer, x, y) {
+                    assert!(get_pixel(&*sim.buffer, x + 1, y), "({x}, {y})");
+                }
+            }
+        }
+        assert!(get_pixel(&*sim.buffer, x1 - 1, ROWS - 1));
+    }
+
+    #[test]
+    fn old_sand_is_drawn_with_a_sparse_dither() {
+        let mut sim = Simulation::new(36);
+        sim.shade_age = Some(3);
+        let floor = ROWS - 1;
+        fill_rect(&mut *sim.buffer, 0, floor, 16, ROWS, true);
+        for _ in 0..5 {
+            sim.step();
+        }
+        // Landed after the floor aged, so it is still fresh
+        sim.set_cell(0, floor - 1, Some(Material::Sand));
+
+        let mut out = [0u8; COLUMNS];
+        sim.render_row(floor, &mut out);
+        assert_eq!(sparse_checker(floor), 0x22);
+        assert_eq!(out[..3], [0xDD, 0xDD, 0x00]);
+        sim.render_row(floor - 1, &mut out);
+        assert_eq!(out[..3], [0x80, 0x00, 0x00]);
+
+        // Without shading old sand stays solid
+        sim.shade_age = None;
+        sim.render_row(floor, &mut out);
+        assert_eq!(out[..3], [0xFF, 0xFF, 0x00]);
+    }
+}
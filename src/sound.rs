@@ -0,0 +1,31 @@
+use pd::sys::ffi::{PDSynth, SoundWaveform};
+
+// Short noise tick used as feedback when grains land
+pub struct Blip {
+    synth: *mut PDSynth,
+}
+
+impl Blip {
+    pub fn new() -> Self {
+        unsafe {
+            let synth = pd::sys::api!(sound.synth.newSynth)();
+            pd::sys::api!(sound.synth.setWaveform)(synth, SoundWaveform::kWaveformNoise);
+            pd::sys::api!(sound.synth.setAttackTime)(synth, 0.0);
+            pd::sys::api!(sound.synth.setReleaseTime)(synth, 0.05);
+            Self { synth }
+        }
+    }
+
+    // Volume is clamped to 0..=1
+    pub fn play(&self, volume: f32) {
+        unsafe {
+            pd::sys::api!(sound.synth.playNote)(
+                self.synth,
+                1760.0,
+                volume.clamp(0.0, 1.0),
+                0.02,
+                0,
+            );
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use alloc::vec::Vec;
+
+use crate::sim::{Simulation, BUFFER_SIZE, PIXEL_WIDTH, ROWS};
+
+// A saved scene is a small header followed by the sand, water, ash and stone
+// planes: magic, format version, then field width and height as
+// little-endian u16. Older versions end early, version 1 after the water
+// plane and version 2 after the ash plane.
+const MAGIC: &[u8; 4] = b"SAND";
+const VERSION: u8 = 3;
+const HEADER_LEN: usize = 9;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    NotAScene,
+    UnknownVersion(u8),
+    WrongSize { width: u16, rows: u16 },
+    Truncated,
+}
+
+fn size_bytes() -> [u8; 4] {
+    let width = u16::try_from(PIXEL_WIDTH).unwrap().to_le_bytes();
+    let rows = u16::try_from(ROWS).unwrap().to_le_bytes();
+    [width[0], width[1], rows[0], rows[1]]
+}
+
+pub fn serialize_state(sim: &Simulation) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(HEADER_LEN + 4 * BUFFER_SIZE);
+    blob.extend_from_slice(MAGIC);
+    blob.push(VERSION);
+    blob.extend_from_slice(&size_bytes());
+    blob.extend_from_slice(&*sim.buffer);
+    blob.extend_from_slice(&*sim.water);
+    blob.extend_from_slice(&*sim.ash);
+    blob.extend_from_slice(&*sim.stone);
+    blob
+}
+
+// Leaves the simulation untouched unless the whole blob is valid
+pub fn load_state(sim: &mut Simulation, blob: &[u8]) -> Result<(), StateError> {
+    if blob.len() < HEADER_LEN {
+        return Err(StateError::Truncated);
+    }
+    if &blob[..4] != MAGIC {
+        return Err(StateError::NotAScene);
+    }
+    let planes = match blob[4] {
+        1 => 2,
+        2 => 3,
+        VERSION => 4,
+        version => return Err(StateError::UnknownVersion(version)),
+    };
+    if blob[5..HEADER_LEN] != size_bytes() {
+        return Err(StateError::WrongSize {
+            width: u16::from_le_bytes([blob[5], blob[6]]),
+            rows: u16::from_le_bytes([blob[7], blob[8]]),
+        });
+    }
+    if blob.len() < HEADER_LEN + planes * BUFFER_SIZE {
+        return Err(StateError::Truncated);
+    }
+
+    let mut saved = blob[HEADER_LEN..].chunks_exact(BUFFER_SIZE);
+    let buffer = saved.next().unwrap();
+    let water = saved.next().unwrap();
+    let ash = if planes >= 3 { saved.next() } else { None };
+    let stone = if planes >= 4 { saved.next() } else { None };
+    sim.buffer.copy_from_slice(buffer);
+    // Materials are only ever set on a grain and never both at once, keep it
+    // that way for hand-edited files
+    for ((water_byte, &saved), &grain) in sim.water.iter_mut().zip(water).zip(buffer) {
+        *water_byte = saved & grain;
+    }
+    match ash {
+        Some(ash) => {
+            for (((ash_byte, &saved), &grain), &water) in sim
+                .ash
+                .iter_mut()
+                .zip(ash)
+                .zip(buffer)
+                .zip(sim.water.iter())
+            {
+                *ash_byte = saved & grain & !water;
+            }
+        }
+        None => sim.ash.fill(0),
+    }
+    match stone {
+        Some(stone) => {
+            for (((stone_byte, &saved), &grain), (&water, &ash)) in sim
+                .stone
+                .iter_mut()
+                .zip(stone)
+                .zip(buffer)
+                .zip(sim.water.iter().zip(sim.ash.iter()))
+            {
+                *stone_byte = saved & grain & !water & !ash;
+            }
+        }
+        None => sim.stone.fill(0),
+    }
+    sim.invalidate();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng;
+    use crate::sim::{scatter, set_pixel, Material};
+
+    fn scene() -> Simulation {
+        let mut sim = Simulation::new(3);
+        scatter(&mut *sim.buffer, 3000, &mut rng::seeded(3));
+        sim.set_cell(10, 20, Some(Material::Water));
+        sim.set_cell(11, 20, Some(Material::Ash));
+        sim.set_cell(12, 20, Some(Material::Stone));
+        sim
+    }
+
+    #[test]
+    fn saved_scene_loads_back() {
+        let saved = scene();
+        let blob = serialize_state(&saved);
+        let mut loaded = Simulation::new(4);
+        assert_eq!(load_state(&mut loaded, &blob), Ok(()));
+        assert_eq!(loaded.fnv_hash_state(), saved.fnv_hash_state());
+    }
+
+    #[test]
+    fn broken_blobs_leave_the_field_alone() {
+        let blob = serialize_state(&scene());
+        let mut sim = Simulation::new(4);
+        set_pixel(&mut *sim.buffer, 0, 0, true);
+        let before = sim.fnv_hash_state();
+
+        assert_eq!(
+            load_state(&mut sim, &blob[..blob.len() - 1]),
+            Err(StateError::Truncated)
+        );
+        assert_eq!(load_state(&mut sim, &blob[..5]), Err(StateError::Truncated));
+        let mut other = blob.clone();
+        other[0] = b'X';
+        assert_eq!(load_state(&mut sim, &other), Err(StateError::NotAScene));
+        other = blob.clone();
+        other[4] = 9;
+        assert_eq!(
+            load_state(&mut sim, &other),
+            Err(StateError::UnknownVersion(9))
+        );
+        other = blob;
+        other[5] = 0;
+        assert!(matches!(
+            load_state(&mut sim, &other),
+            Err(StateError::WrongSize { .. })
+        ));
+        assert_eq!(sim.fnv_hash_state(), before);
+    }
+}